@@ -1,9 +1,8 @@
+import { op_plugin_log } from "ext:core/ops";
 import * as console from "ext:deno_console/01_console.js";
 
 Object.defineProperty(globalThis, "console", {
-  value: new console.Console((msg, level) =>
-    globalThis.Deno.core.print(msg, level > 1)
-  ),
+  value: new console.Console((msg, level) => op_plugin_log(msg, level)),
   enumerable: false,
   configurable: true,
   writable: true,
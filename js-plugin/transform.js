@@ -1,5 +1,26 @@
 // JavaScript transformation plugin
 
+const customersTable = {
+  name: "customers",
+  schema: "public",
+  columns: [
+    { name: "customer_id", type: "string" },
+    { name: "customer_name", type: "string" }
+  ]
+};
+
+const ordersTable = {
+  name: "orders",
+  schema: "public",
+  columns: [
+    { name: "order_id", type: "string" },
+    { name: "customer_id", type: "string" },
+    { name: "customer_name", type: "string" },
+    { name: "total_items", type: "integer" },
+    { name: "total_price", type: "decimal" },
+  ]
+};
+
 /**
  * Transform messages from Kafka before inserting into PostgreSQL
  * @param {Array} inputs - Array of input messages from Kafka
@@ -14,31 +35,23 @@ function transform(inputs) {
       };
     }
 
-    // Determine the table type based on the first valid input
-    let tableInfo = null;
-    const transformedData = [];
-    let hasError = false;
-    let errorMessage = "";
+    // Customers and orders in the same batch go to their own tables
+    const customers = [];
+    const orders = [];
+    // Index of the input each row came from, so rejected rows can be traced back
+    const customerInputs = [];
+    const orderInputs = [];
+    const outcomes = [];
 
-    for (const input of inputs) {
+    for (const [index, input] of inputs.entries()) {
       try {
         // Handle customer data directly
         if (input.id && input.name) {
-          if (!tableInfo) {
-            tableInfo = {
-              name: "customers",
-              schema: "public",
-              columns: [
-                { name: "customer_id", type: "string" },
-                { name: "customer_name", type: "string" }
-              ]
-            };
-          }
-
-          transformedData.push({
+          customers.push({
             customer_id: input.id,
             customer_name: input.name
           });
+          customerInputs.push(index);
         }
         // Extract customer and order data if present
         else if (input.customer && input.order) {
@@ -47,12 +60,12 @@ function transform(inputs) {
           const customerName = input.customer.name;
 
           if (customerId === undefined) {
-            console.log("Missing customer id");
+            outcomes.push({ index, status: "failed", error: "Missing customer id" });
             continue;
           }
 
           if (customerName === undefined) {
-            console.log("Missing customer name");
+            outcomes.push({ index, status: "failed", error: "Missing customer name" });
             continue;
           }
 
@@ -61,12 +74,12 @@ function transform(inputs) {
           const items = input.order.items;
 
           if (orderId === undefined) {
-            console.log("Missing order id");
+            outcomes.push({ index, status: "failed", error: "Missing order id" });
             continue;
           }
 
           if (!Array.isArray(items)) {
-            console.log("Missing order items");
+            outcomes.push({ index, status: "failed", error: "Missing order items" });
             continue;
           }
 
@@ -85,48 +98,26 @@ function transform(inputs) {
           // Log using the Rust op (simplified to avoid recursion)
           console.log(`Processing order ${orderId} for customer ${customerName}`);
 
-          if (!tableInfo) {
-            tableInfo = {
-              name: "orders",
-              schema: "public",
-              columns: [
-                { name: "order_id", type: "string" },
-                { name: "customer_id", type: "string" },
-                { name: "customer_name", type: "string" },
-                { name: "total_items", type: "integer" },
-                { name: "total_price", type: "decimal" },
-              ]
-            };
-          }
-
-          transformedData.push({
+          orders.push({
             order_id: orderId,
             customer_id: customerId,
             customer_name: customerName,
             total_items: totalItems,
             total_price: totalPrice
           });
+          orderInputs.push(index);
         }
-        // Simple pass-through for other data
+        // Skip anything that isn't a customer or an order
         else {
-          if (!tableInfo) {
-            tableInfo = {
-              name: "generic",
-              schema: "public",
-              columns: []
-            };
-          }
-
-          // transformedData.push(input);
-          console.log(`Error processing item: ${itemError.message}`);
+          outcomes.push({ index, status: "skipped", reason: "Not a customer or order" });
         }
       } catch (itemError) {
-        console.log(`Error processing item: ${itemError.message}`);
-        // Continue processing other items instead of failing the whole batch
+        // Fail this input instead of the whole batch
+        outcomes.push({ index, status: "failed", error: itemError.message });
       }
     }
 
-    if (transformedData.length === 0) {
+    if (customers.length === 0 && orders.length === 0 && outcomes.length === 0) {
       return {
         success: false,
         error: "No valid data to transform"
@@ -135,8 +126,11 @@ function transform(inputs) {
 
     return {
       success: true,
-      table_info: tableInfo,
-      data: transformedData
+      tables: [
+        { table_info: customersTable, rows: customers, inputs: customerInputs },
+        { table_info: ordersTable, rows: orders, inputs: orderInputs }
+      ],
+      outcomes
     };
   } catch (error) {
     // Log the error using a simpler approach
@@ -0,0 +1,46 @@
+use core_affinity::CoreId;
+use tracing::{debug, warn};
+
+/// Returns the cores threads may be pinned to: the requested set, or every core on the machine
+pub fn available_cores(requested: Option<&[usize]>) -> Vec<usize> {
+    match requested {
+        Some(cores) => cores.to_vec(),
+        None => core_affinity::get_core_ids()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|core| core.id)
+            .collect(),
+    }
+}
+
+/// Splits `cores` so plugin workers and runtime threads land on distinct cores where possible
+///
+/// Workers take the first `worker_count` cores and the runtime gets the rest. When there are no
+/// cores left over the runtime shares the full set.
+pub fn split_cores(cores: &[usize], worker_count: usize) -> (Vec<usize>, Vec<usize>) {
+    let split = worker_count.min(cores.len());
+    let (workers, runtime) = cores.split_at(split);
+
+    if runtime.is_empty() {
+        (workers.to_vec(), cores.to_vec())
+    } else {
+        (workers.to_vec(), runtime.to_vec())
+    }
+}
+
+/// Pins the calling thread to one of `cores`, chosen round-robin by `index`
+pub fn pin_current_thread(cores: &[usize], index: usize) {
+    if cores.is_empty() {
+        return;
+    }
+
+    let core = cores[index % cores.len()];
+    if core_affinity::set_for_current(CoreId { id: core }) {
+        debug!(
+            "Pinned thread {:?} to core {core}",
+            std::thread::current().name()
+        );
+    } else {
+        warn!("Failed to pin thread to core {core}");
+    }
+}
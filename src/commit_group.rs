@@ -0,0 +1,257 @@
+//! Writes the results of several batches in one transaction, so they share the cost of a commit
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_postgres::Client;
+use tracing::{debug, warn};
+
+use crate::deno::TransformResult;
+use crate::postgres::{self, Inserted, Pool};
+use crate::statement_cache::PooledClient;
+
+/// Batches written together, each with a caller's tag such as its Kafka offset
+///
+/// Results are written into a transaction that stays open until the group is due. Batches
+/// are only settled, with the number of rows they inserted or why they failed, once that
+/// transaction commits or is given up on. If any write or the commit fails, the transaction
+/// is rolled back and each batch of the group is retried on its own, so one bad batch fails
+/// alone and its rejected rows are found as in [`postgres::insert_data`].
+pub struct CommitGroup<T> {
+    every_batches: Option<usize>,
+    interval: Option<Duration>,
+    connection: Option<deadpool_postgres::Client>,
+    opened_at: Option<Instant>,
+    pending: Vec<Pending<T>>,
+}
+
+struct Pending<T> {
+    tag: T,
+    result: TransformResult,
+    inserted: u64,
+}
+
+/// Tags of settled batches, with the rows each inserted or the error it failed with
+pub type Settled<T> = Vec<(T, Result<Inserted>)>;
+
+// The group's connection while its transaction is open, where a failed statement can't be retried
+struct OpenTransaction<'a>(&'a deadpool_postgres::Client);
+
+impl PooledClient for OpenTransaction<'_> {
+    fn client(&self) -> &Client {
+        self.0
+    }
+
+    fn connection(&self) -> &Arc<deadpool_postgres::StatementCache> {
+        &self.0.statement_cache
+    }
+
+    fn can_retry(&self) -> bool {
+        false
+    }
+}
+
+impl<T> CommitGroup<T> {
+    /// Creates a group with the pool's commit grouping settings
+    pub fn new(pool: &Pool) -> Self {
+        let (every_batches, interval) = pool.commit_grouping();
+        Self {
+            every_batches,
+            interval,
+            connection: None,
+            opened_at: None,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Whether no batch is waiting for the group to commit
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// When the open transaction has to commit, if there is one with a time limit
+    pub fn deadline(&self) -> Option<Instant> {
+        Some(self.opened_at? + self.interval?)
+    }
+
+    /// Writes a batch into the group, returning every batch settled in the process
+    ///
+    /// Without commit grouping the batch is inserted and settled right away.
+    pub async fn write(&mut self, pool: &Pool, tag: T, result: TransformResult) -> Settled<T> {
+        if self.every_batches.is_none() && self.interval.is_none() {
+            let inserted = postgres::insert_data(pool, &result).await;
+            return vec![(tag, inserted)];
+        }
+
+        let mut settled = Vec::new();
+
+        // A migration commits its own transaction, so the group has to commit first
+        if !self.pending.is_empty() && pool.needs_migration(&result) {
+            settled.extend(self.commit(pool).await);
+        }
+
+        match self.write_pending(pool, &result).await {
+            Ok(inserted) => self.pending.push(Pending {
+                tag,
+                result,
+                inserted,
+            }),
+            Err(WriteError::Batch(e)) => settled.push((tag, Err(e))),
+            Err(WriteError::Group(e)) => {
+                warn!(
+                    "Rolling back {} grouped batches: {e:#}",
+                    self.pending.len() + 1
+                );
+                self.pending.push(Pending {
+                    tag,
+                    result,
+                    inserted: 0,
+                });
+                settled.extend(self.retry_alone(pool).await);
+            }
+        }
+
+        if self.is_due() {
+            settled.extend(self.commit(pool).await);
+        }
+
+        settled
+    }
+
+    /// Commits the open transaction, settling every pending batch
+    pub async fn commit(&mut self, pool: &Pool) -> Settled<T> {
+        if self.pending.is_empty() {
+            return Vec::new();
+        }
+
+        let committed = match &self.connection {
+            Some(connection) => connection
+                .batch_execute("COMMIT")
+                .await
+                .context("Failed to commit grouped batches"),
+            None => Err(anyhow::anyhow!("Grouped batches lost their connection")),
+        };
+
+        if let Err(e) = committed {
+            warn!("Retrying {} grouped batches: {e:#}", self.pending.len());
+            return self.retry_alone(pool).await;
+        }
+
+        debug!("Committed {} grouped batches", self.pending.len());
+        self.connection = None;
+        self.opened_at = None;
+        self.pending
+            .drain(..)
+            .map(|pending| {
+                let inserted = Inserted {
+                    rows: pending.inserted,
+                    ..Inserted::default()
+                };
+                (pending.tag, Ok(inserted))
+            })
+            .collect()
+    }
+
+    fn is_due(&self) -> bool {
+        let batches_due = self
+            .every_batches
+            .is_some_and(|batches| self.pending.len() >= batches);
+        let time_due = self
+            .deadline()
+            .is_some_and(|deadline| Instant::now() >= deadline);
+
+        batches_due || time_due
+    }
+
+    async fn write_pending(
+        &mut self,
+        pool: &Pool,
+        result: &TransformResult,
+    ) -> Result<u64, WriteError> {
+        let groups = postgres::result_groups(pool, result).map_err(WriteError::Batch)?;
+
+        let mut connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => pool.connection().await.map_err(WriteError::Batch)?,
+        };
+        let in_transaction = self.opened_at.is_some();
+
+        let tables = match postgres::prepare_tables(pool, &mut connection, &groups).await {
+            Ok(tables) => tables,
+            // A failed query aborts the open transaction, while a rejected value leaves it usable
+            Err(e) if in_transaction => {
+                self.connection = Some(connection);
+                return Err(if is_sql_error(&e) {
+                    WriteError::Group(e)
+                } else {
+                    WriteError::Batch(e)
+                });
+            }
+            Err(e) => return Err(discard(connection, e)),
+        };
+
+        if !in_transaction {
+            let begin = connection.batch_execute("BEGIN").await;
+            if let Err(e) = begin {
+                let e = anyhow::Error::new(e).context("Failed to start transaction");
+                return Err(discard(connection, e));
+            }
+            self.opened_at = Some(Instant::now());
+        }
+
+        let connection = self.connection.insert(connection);
+        postgres::write_tables(pool, &OpenTransaction(connection), &tables)
+            .await
+            .map_err(WriteError::Group)
+    }
+
+    // Rolls the group back and inserts each of its batches in a transaction of its own
+    async fn retry_alone(&mut self, pool: &Pool) -> Settled<T> {
+        if let Some(connection) = self.connection.take()
+            && let Err(e) = connection.batch_execute("ROLLBACK").await
+        {
+            // The connection is dropped from the pool rather than returned mid-transaction
+            warn!("Failed to roll back grouped batches: {e}");
+            drop(deadpool_postgres::Object::take(connection));
+        }
+        self.opened_at = None;
+
+        let mut settled = Vec::with_capacity(self.pending.len());
+        for pending in self.pending.drain(..) {
+            let inserted = postgres::insert_data(pool, &pending.result).await;
+            settled.push((pending.tag, inserted));
+        }
+        settled
+    }
+}
+
+impl<T> Drop for CommitGroup<T> {
+    fn drop(&mut self) {
+        // A connection in the middle of a transaction must not go back to the pool
+        if self.opened_at.is_some()
+            && let Some(connection) = self.connection.take()
+        {
+            drop(deadpool_postgres::Object::take(connection));
+        }
+    }
+}
+
+enum WriteError {
+    /// Only the batch being written failed, and the group's transaction is intact
+    Batch(anyhow::Error),
+    /// The group's transaction was aborted
+    Group(anyhow::Error),
+}
+
+// Closes a connection that failed outside of a transaction instead of returning it to the pool,
+// since the failure may be the connection itself, such as after a server restart
+fn discard(connection: deadpool_postgres::Client, error: anyhow::Error) -> WriteError {
+    drop(deadpool_postgres::Object::take(connection));
+    WriteError::Batch(error)
+}
+
+fn is_sql_error(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .any(|cause| cause.is::<tokio_postgres::Error>())
+}
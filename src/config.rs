@@ -1,3 +1,8 @@
+use anyhow::{Context, Result, bail};
+use serde_json::{Map, Value};
+use std::path::Path;
+use tracing::warn;
+
 use crate::postgres;
 
 pub struct AppConfig {
@@ -7,3 +12,46 @@ pub struct AppConfig {
     pub pg_pool: postgres::Pool,
     pub group_id: String,
 }
+
+/// Builds the configuration exposed to plugins as `globalThis.config` and passed to `init`
+///
+/// The file may be JSON or TOML (by `.toml` extension). Whitelisted environment variables are
+/// added under an `env` key, so secrets don't have to be written into the file.
+pub fn load_plugin_config(path: Option<&Path>, env_vars: &[String]) -> Result<Value> {
+    let mut config = match path {
+        Some(path) => {
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read plugin config {path:?}"))?;
+
+            if path.extension().is_some_and(|ext| ext == "toml") {
+                toml::from_str(&contents)
+                    .with_context(|| format!("Failed to parse plugin config {path:?} as TOML"))?
+            } else {
+                serde_json::from_str(&contents)
+                    .with_context(|| format!("Failed to parse plugin config {path:?} as JSON"))?
+            }
+        }
+        None => Value::Object(Map::new()),
+    };
+
+    if env_vars.is_empty() {
+        return Ok(config);
+    }
+
+    let Value::Object(fields) = &mut config else {
+        bail!("Plugin config must be an object to expose environment variables");
+    };
+
+    let mut env = Map::new();
+    for name in env_vars {
+        match std::env::var(name) {
+            Ok(value) => {
+                env.insert(name.clone(), Value::String(value));
+            }
+            Err(_) => warn!("Environment variable {name} for plugin config is not set"),
+        }
+    }
+    fields.insert("env".to_string(), Value::Object(env));
+
+    Ok(config)
+}
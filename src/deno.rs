@@ -1,143 +1,793 @@
-use anyhow::Context;
+use anyhow::{Context, bail};
 use deno_core::v8;
-use deno_core::{FastString, JsRuntime, RuntimeOptions, extension};
+use deno_core::{
+    Extension, FastString, JsRuntime, JsRuntimeForSnapshot, OpState, PollEventLoopOptions,
+    RuntimeOptions, extension, op2,
+};
 use num_cpus;
 use serde_json::Value;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::sync::mpsc::{Sender, channel};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, channel, sync_channel};
+use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
-use tokio::sync::{Mutex, oneshot};
-use tracing::warn;
+use std::time::{Duration, Instant};
+use tokio::sync::{Notify, oneshot};
+use tracing::{Level, error, event, info, warn};
+
+use crate::affinity;
 
 extension!(
     init_console,
     deps = [deno_console],
+    ops = [op_plugin_log],
     esm_entry_point = "ext:init_console/js-plugin/init.js",
     esm = ["js-plugin/init.js"],
+    state = |state| {
+        state.put(LogContext::default());
+    },
     docs = "Init"
 );
 
+static NEXT_BATCH_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Fields attached to every line a plugin writes to `console`
+#[derive(Debug, Default)]
+struct LogContext {
+    plugin: String,
+    worker: Option<usize>,
+    batch: Option<u64>,
+}
+
+// Receives console output from init.js. Levels follow deno_console's printer: 0 is debug,
+// 1 is log/info, 2 is warn and anything higher is error.
+#[op2(fast)]
+fn op_plugin_log(state: &mut OpState, #[string] message: &str, level: u32) {
+    let context = state.borrow::<LogContext>();
+    let message = message.trim_end_matches('\n');
+
+    macro_rules! plugin_event {
+        ($level:expr) => {
+            event!(
+                target: "plugin",
+                $level,
+                plugin = %context.plugin,
+                worker = context.worker,
+                batch = context.batch,
+                "{message}"
+            )
+        };
+    }
+
+    match level {
+        0 => plugin_event!(Level::DEBUG),
+        1 => plugin_event!(Level::INFO),
+        2 => plugin_event!(Level::WARN),
+        _ => plugin_event!(Level::ERROR),
+    }
+}
+
+// Only the console is provided, so plugins have no timers or other async ops to await
+fn extensions() -> Vec<Extension> {
+    vec![deno_console::deno_console::init(), init_console::init()]
+}
+
+/// Builds a V8 startup snapshot with `deno_console` and the plugin's top-level code already run
+///
+/// Runtimes started from the snapshot skip re-evaluating the plugin. V8 requires the snapshot
+/// to outlive every isolate created from it.
+pub fn create_snapshot(plugin_path: &Path, config: &Value) -> anyhow::Result<Box<[u8]>> {
+    let js_code =
+        std::fs::read_to_string(plugin_path).context("Failed to read JavaScript plugin file")?;
+
+    let mut runtime = JsRuntimeForSnapshot::new(RuntimeOptions {
+        extensions: extensions(),
+        ..Default::default()
+    });
+
+    define_config(&mut runtime, config)?;
+    runtime
+        .execute_script("<anon>", FastString::from(js_code))
+        .context("Failed to execute JavaScript plugin for snapshot")?;
+
+    Ok(runtime.snapshot())
+}
+
+// Snapshot bytes owned by a pool, handed to its runtimes as `&'static` since V8 wants them to
+// outlive every isolate, and freed once the pool's workers have joined
+struct OwnedSnapshot(NonNull<[u8]>);
+
+// SAFETY: the bytes are only freed on drop, which needs ownership
+unsafe impl Send for OwnedSnapshot {}
+// SAFETY: the bytes are never written after the snapshot is built
+unsafe impl Sync for OwnedSnapshot {}
+
+impl OwnedSnapshot {
+    fn new(bytes: Box<[u8]>) -> Self {
+        Self(NonNull::from(Box::leak(bytes)))
+    }
+
+    /// # Safety
+    ///
+    /// Every runtime started from the returned bytes must be dropped before the snapshot is.
+    unsafe fn bytes(&self) -> &'static [u8] {
+        // SAFETY: the pointer came from a leaked box that lives until this is dropped
+        unsafe { self.0.as_ref() }
+    }
+}
+
+impl Drop for OwnedSnapshot {
+    fn drop(&mut self) {
+        // SAFETY: the pointer came from `Box::leak` and the caller of `bytes` ensured no
+        // runtime still uses it
+        drop(unsafe { Box::from_raw(self.0.as_ptr()) });
+    }
+}
+
+// Exposes the plugin config as a deeply frozen global before the plugin's code runs
+fn define_config(runtime: &mut JsRuntime, config: &Value) -> anyhow::Result<()> {
+    let js_code = format!(
+        r#"
+        (() => {{
+          const freeze = (value) => {{
+            if (value !== null && typeof value === "object") {{
+              Object.values(value).forEach(freeze);
+              Object.freeze(value);
+            }}
+            return value;
+          }};
+          Object.defineProperty(globalThis, "config", {{
+            value: freeze({config}),
+            enumerable: false,
+            configurable: false,
+            writable: false,
+          }});
+        }})();
+        "#
+    );
+
+    runtime
+        .execute_script("<plugin_config>", FastString::from(js_code))
+        .context("Failed to define plugin config")?;
+
+    Ok(())
+}
+
 pub struct DenoRuntime {
     runtime: JsRuntime,
+    heap_limit_reached: Arc<AtomicBool>,
+    hook_timeout: Option<Duration>,
+    hook_timed_out: bool,
 }
 
 impl DenoRuntime {
     pub fn new(plugin_path: &Path) -> anyhow::Result<Self> {
+        Self::with_options(plugin_path, &PluginOptions::default())
+    }
+
+    pub fn with_options(plugin_path: &Path, options: &PluginOptions) -> anyhow::Result<Self> {
         // Read the JavaScript file
         let js_code = std::fs::read_to_string(plugin_path)
             .context("Failed to read JavaScript plugin file")?;
 
-        let mut runtime = JsRuntime::new(RuntimeOptions {
-            extensions: vec![deno_console::deno_console::init(), init_console::init()],
-            ..Default::default()
-        });
+        let mut runtime = Self::create(None, options);
+        runtime.set_log_context(plugin_path, None);
+        define_config(&mut runtime.runtime, &options.config)?;
 
         // Execute the JavaScript code
         runtime
+            .runtime
             .execute_script("<anon>", FastString::from(js_code.to_string()))
             .context("Failed to execute JavaScript plugin for runtime")?;
 
-        Ok(Self { runtime })
+        Ok(runtime)
     }
 
-    pub fn execute(&mut self, values: Vec<Value>) -> anyhow::Result<TransformResult> {
+    /// Starts a runtime from a snapshot made by `create_snapshot`
+    pub fn from_snapshot(snapshot: &'static [u8], options: &PluginOptions) -> anyhow::Result<Self> {
+        Ok(Self::create(Some(snapshot), options))
+    }
+
+    fn create(startup_snapshot: Option<&'static [u8]>, options: &PluginOptions) -> Self {
+        let create_params = options.max_heap_size.map(|max_heap_size| {
+            v8::CreateParams::default().heap_limits(options.initial_heap_size, max_heap_size)
+        });
+
+        let mut runtime = JsRuntime::new(RuntimeOptions {
+            extensions: extensions(),
+            startup_snapshot,
+            create_params,
+            ..Default::default()
+        });
+
+        // Terminate the running batch instead of letting V8 abort the process when the plugin
+        // runs out of heap. The limit is raised so the termination has room to unwind.
+        let heap_limit_reached = Arc::new(AtomicBool::new(false));
+        let isolate = runtime.v8_isolate().thread_safe_handle();
+        let reached = heap_limit_reached.clone();
+        runtime.add_near_heap_limit_callback(move |current_limit, _initial_limit| {
+            warn!("Plugin is near its V8 heap limit ({current_limit} bytes), terminating batch");
+            reached.store(true, Ordering::SeqCst);
+            isolate.terminate_execution();
+            current_limit * 2
+        });
+
+        Self {
+            runtime,
+            heap_limit_reached,
+            hook_timeout: options.batch_timeout,
+            hook_timed_out: false,
+        }
+    }
+
+    pub async fn execute(&mut self, values: Vec<Value>) -> anyhow::Result<TransformResult> {
+        let batch = NEXT_BATCH_ID.fetch_add(1, Ordering::Relaxed);
+        self.update_log_context(|context| context.batch = Some(batch));
+        let result = self.transform_batch(values).await;
+        self.update_log_context(|context| context.batch = None);
+        self.check_heap_limit(result)
+    }
+
+    /// Sets the plugin name and worker id attached to the plugin's console output
+    pub fn set_log_context(&mut self, plugin_path: &Path, worker: Option<usize>) {
+        let plugin = plugin_path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        self.update_log_context(|context| {
+            context.plugin = plugin;
+            context.worker = worker;
+        });
+    }
+
+    fn update_log_context(&mut self, update: impl FnOnce(&mut LogContext)) {
+        let state = self.runtime.op_state();
+        update(state.borrow_mut().borrow_mut::<LogContext>());
+    }
+
+    /// Calls the plugin's optional `init(config)` hook, once per isolate
+    pub async fn init(&mut self, config: &Value) -> anyhow::Result<()> {
+        let result = self.call_hook("init", config).await.map(|_| ());
+        self.check_heap_limit(result)
+    }
+
+    /// Calls the plugin's optional `flush()` hook and returns any rows it had buffered
+    pub async fn flush(&mut self) -> anyhow::Result<Option<TransformResult>> {
+        let result = self
+            .call_hook("flush", &Value::Null)
+            .await
+            .and_then(|result_str| {
+                serde_json::from_str(&result_str)
+                    .context("Failed to parse JavaScript flush results as JSON")
+            });
+        self.check_heap_limit(result)
+    }
+
+    /// Calls the plugin's optional `shutdown()` hook before the isolate is dropped
+    pub async fn shutdown(&mut self) -> anyhow::Result<()> {
+        let result = self.call_hook("shutdown", &Value::Null).await.map(|_| ());
+        self.check_heap_limit(result)
+    }
+
+    /// Whether the near-heap-limit callback has fired, after which the runtime must be replaced
+    pub fn heap_limit_reached(&self) -> bool {
+        self.heap_limit_reached.load(Ordering::SeqCst)
+    }
+
+    /// Whether JavaScript was terminated outside of a batch, so the runtime must be replaced
+    pub fn needs_restart(&self) -> bool {
+        self.heap_limit_reached() || self.hook_timed_out
+    }
+
+    // Report heap exhaustion rather than the generic termination error it caused
+    fn check_heap_limit<T>(&self, result: anyhow::Result<T>) -> anyhow::Result<T> {
+        if self.heap_limit_reached() {
+            bail!("Plugin exceeded its V8 heap limit and was terminated");
+        }
+
+        result
+    }
+
+    async fn transform_batch(&mut self, values: Vec<Value>) -> anyhow::Result<TransformResult> {
         // Convert the messages to a JSON array string
         let messages_json = serde_json::to_string(&values)?;
 
-        // Create the JavaScript code to call the transform function for each message in the batch
+        // Create the JavaScript code to call the transform function for each message in the batch.
+        // Wrapping the result in Promise.resolve lets plugins return either a plain object or a
+        // Promise from an async transform.
         let js_code = format!(
             r#"
             var inputs = {};
-            JSON.stringify(transform(inputs));
+            Promise.resolve(transform(inputs)).then((result) => JSON.stringify(result));
             "#,
             messages_json
         );
 
+        let result_str = self
+            .evaluate("<transform_batch>", js_code)
+            .await
+            .context("Failed to call transform function in JavaScript plugin for batch")?;
+
+        // Parse the result as JSON array
+        let mut transform_results: TransformResult = serde_json::from_str(&result_str)
+            .context("Failed to parse JavaScript batch results as JSON")?;
+        transform_results.attach_failed_inputs(&values);
+
+        Ok(transform_results)
+    }
+
+    // Calls a lifecycle hook if the plugin defines it, resolving to "null" when it doesn't.
+    // Hooks get the batch timeout too, so one that never returns can't hold up its worker.
+    async fn call_hook(&mut self, hook: &str, argument: &Value) -> anyhow::Result<String> {
+        let js_code = format!(
+            r#"
+            Promise.resolve(
+              typeof globalThis.{hook} === "function" ? globalThis.{hook}({argument}) : undefined
+            ).then((result) => JSON.stringify(result ?? null));
+            "#
+        );
+
+        let Some(timeout) = self.hook_timeout else {
+            return self
+                .evaluate("<lifecycle_hook>", js_code)
+                .await
+                .with_context(|| format!("Failed to call {hook} hook in JavaScript plugin"));
+        };
+
+        let watchdog = HookWatchdog::start(self.isolate_handle(), timeout);
+        let result =
+            tokio::time::timeout(timeout, self.evaluate("<lifecycle_hook>", js_code)).await;
+        let terminated = watchdog.finish();
+
+        match result {
+            Ok(result) if !terminated => {
+                result.with_context(|| format!("Failed to call {hook} hook in JavaScript plugin"))
+            }
+            _ => {
+                self.hook_timed_out = true;
+                bail!("Plugin {hook} hook timed out after {timeout:?}")
+            }
+        }
+    }
+
+    // Runs a script, drives the event loop until its promise settles and returns it as a string
+    async fn evaluate(&mut self, name: &'static str, js_code: String) -> anyhow::Result<String> {
         // Execute the JavaScript code on the appropriate runtime
+        let promise = self
+            .runtime
+            .execute_script(name, FastString::from(js_code))
+            .inspect_err(log_js_error)?;
+
+        // Drive the event loop until the returned promise settles
+        let resolve = self.runtime.resolve(promise);
         let result = self
             .runtime
-            .execute_script("<transform_batch>", FastString::from(js_code.to_string()))
-            .inspect_err(|e| {
-                if let deno_core::error::CoreError::Js(js_error) = e {
-                    warn!("Javascript Error in batch processing: {js_error}");
-                }
-            })
-            .with_context(|| "Failed to call transform function in JavaScript plugin for batch")?;
+            .with_event_loop_promise(resolve, PollEventLoopOptions::default())
+            .await
+            .inspect_err(log_js_error)?;
 
         // Get the result from the JavaScript execution
         let scope = &mut self.runtime.handle_scope();
         let local = v8::Local::new(scope, result);
-        let result_str = local.to_string(scope).unwrap().to_rust_string_lossy(scope);
+        Ok(local.to_string(scope).unwrap().to_rust_string_lossy(scope))
+    }
 
-        // Parse the result as JSON array
-        let transform_results: TransformResult = serde_json::from_str(&result_str)
-            .context("Failed to parse JavaScript batch results as JSON")?;
+    /// Handle that can terminate JavaScript running on this runtime from another thread
+    pub fn isolate_handle(&mut self) -> v8::IsolateHandle {
+        self.runtime.v8_isolate().thread_safe_handle()
+    }
+}
 
-        Ok(transform_results)
+/// Terminates a lifecycle hook's JavaScript once it runs past its deadline
+///
+/// The hook blocks the worker's thread while it runs, so the deadline is kept on another one.
+struct HookWatchdog {
+    done: Sender<()>,
+    handle: JoinHandle<bool>,
+}
+
+impl HookWatchdog {
+    fn start(isolate: v8::IsolateHandle, timeout: Duration) -> Self {
+        let (done, finished) = channel();
+        let handle = thread::spawn(move || match finished.recv_timeout(timeout) {
+            Err(RecvTimeoutError::Timeout) => {
+                isolate.terminate_execution();
+                true
+            }
+            _ => false,
+        });
+
+        Self { done, handle }
+    }
+
+    /// Stops watching, returns true if the hook was terminated
+    fn finish(self) -> bool {
+        drop(self.done);
+        self.handle.join().unwrap_or(true)
+    }
+}
+
+fn log_js_error(e: &deno_core::error::CoreError) {
+    if let deno_core::error::CoreError::Js(js_error) = e {
+        warn!("Javascript Error in batch processing: {js_error}");
+    }
+}
+
+/// Options controlling how plugin workers execute batches
+#[derive(Clone, Debug, Default)]
+pub struct PluginOptions {
+    /// Number of worker threads, defaults to the number of CPUs
+    pub worker_count: Option<usize>,
+    /// Maximum number of batches waiting for a worker, defaults to twice the worker count
+    pub queue_capacity: Option<usize>,
+    /// Maximum time a single batch or lifecycle hook may run before its isolate is terminated
+    pub batch_timeout: Option<Duration>,
+    /// Initial V8 heap size in bytes, only used when `max_heap_size` is set
+    pub initial_heap_size: usize,
+    /// Maximum V8 heap size in bytes for each worker's isolate
+    pub max_heap_size: Option<usize>,
+    /// Cores to pin worker threads to, assigned round-robin by worker index
+    pub worker_cores: Option<Vec<usize>>,
+    /// Start workers from a V8 snapshot of the loaded plugin instead of evaluating it each time
+    pub startup_snapshot: bool,
+    /// Configuration exposed as a frozen `globalThis.config` and passed to `init(config)`
+    pub config: Value,
+    /// Flush plugin buffers at least this often
+    pub flush_interval: Option<Duration>,
+    /// Flush plugin buffers after this many transformed batches
+    pub flush_every_batches: Option<usize>,
+}
+
+/// Decides when plugin buffers should be flushed, by batch count or elapsed time
+pub struct FlushSchedule {
+    interval: Option<tokio::time::Interval>,
+    every_batches: Option<usize>,
+    since_flush: usize,
+}
+
+impl FlushSchedule {
+    pub fn new(options: &PluginOptions) -> Self {
+        let interval = options.flush_interval.map(|period| {
+            let mut interval =
+                tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            interval
+        });
+
+        Self {
+            interval,
+            every_batches: options.flush_every_batches,
+            since_flush: 0,
+        }
+    }
+
+    /// Records a transformed batch, returns true once enough batches have passed to flush
+    pub fn record_batch(&mut self) -> bool {
+        self.since_flush += 1;
+        self.every_batches
+            .is_some_and(|every| self.since_flush >= every)
+    }
+
+    /// Resolves when the flush interval elapses, or never if there isn't one
+    pub async fn tick(&mut self) {
+        match &mut self.interval {
+            Some(interval) => {
+                interval.tick().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Starts counting again after a flush
+    pub fn reset(&mut self) {
+        self.since_flush = 0;
+        if let Some(interval) = &mut self.interval {
+            interval.reset();
+        }
+    }
+}
+
+// Where workers load the plugin from
+#[derive(Clone)]
+struct PluginSource {
+    path: PathBuf,
+    snapshot: Option<&'static [u8]>,
+}
+
+impl PluginSource {
+    /// Creates a runtime and runs the plugin's `init` hook on the worker's event loop
+    fn load(
+        &self,
+        worker: usize,
+        options: &PluginOptions,
+        tokio_runtime: &tokio::runtime::Runtime,
+    ) -> anyhow::Result<DenoRuntime> {
+        let mut runtime = match self.snapshot {
+            Some(snapshot) => DenoRuntime::from_snapshot(snapshot, options)?,
+            None => DenoRuntime::with_options(&self.path, options)?,
+        };
+        runtime.set_log_context(&self.path, Some(worker));
+
+        tokio_runtime.block_on(runtime.init(&options.config))?;
+        Ok(runtime)
     }
 }
 
-// Task to be executed by a worker
-enum WorkerTask {
-    Execute(Vec<Value>, oneshot::Sender<anyhow::Result<TransformResult>>),
+// Lifecycle of a batch, shared between the pool and the worker running it
+enum BatchState {
+    Queued,
+    Running(v8::IsolateHandle),
+    Done,
+    Cancelled,
+    TimedOut,
+}
+
+/// Tracks which isolate a batch is running on so a watchdog can terminate it
+struct BatchGuard {
+    state: Mutex<BatchState>,
+    started: Notify,
+}
+
+impl BatchGuard {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(BatchState::Queued),
+            started: Notify::new(),
+        }
+    }
+
+    /// Marks the batch as running on the given isolate, returns false if it was cancelled
+    fn start(&self, isolate: v8::IsolateHandle) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            BatchState::Queued => {
+                *state = BatchState::Running(isolate);
+                self.started.notify_one();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Marks the batch as finished, returns true if it was terminated by the watchdog
+    fn finish(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            BatchState::TimedOut => true,
+            _ => {
+                *state = BatchState::Done;
+                false
+            }
+        }
+    }
+
+    /// Terminates the batch if it is still running, or cancels it if it hasn't started
+    fn time_out(&self) {
+        let mut state = self.state.lock().unwrap();
+        match &*state {
+            BatchState::Running(isolate) => {
+                isolate.terminate_execution();
+                *state = BatchState::TimedOut;
+            }
+            BatchState::Queued => *state = BatchState::Cancelled,
+            _ => {}
+        }
+    }
+}
+
+// Batch waiting in the shared queue for the next idle worker
+struct Job {
+    values: Vec<Value>,
+    guard: Arc<BatchGuard>,
+    response: oneshot::Sender<anyhow::Result<TransformResult>>,
+}
+
+// Requests addressed to one specific worker, handled between batches
+enum Control {
+    Flush(oneshot::Sender<anyhow::Result<Option<TransformResult>>>),
     Shutdown,
 }
 
-// Worker that runs in its own thread
+// Next thing for a worker to do, control requests take priority over queued batches
+enum Message {
+    Job(Job),
+    Control(Control),
+}
+
+/// Rows plugins handed over in a flush, along with why any worker failed to flush
+#[derive(Debug, Default)]
+pub struct Flushed {
+    pub results: Vec<TransformResult>,
+    pub errors: Vec<anyhow::Error>,
+}
+
+/// Health of a single plugin worker
+#[derive(Clone, Debug)]
+pub struct WorkerHealth {
+    pub alive: bool,
+    pub restarts: u32,
+}
+
+/// Point-in-time view of the pool's queue and workers
+#[derive(Clone, Debug)]
+pub struct PoolMetrics {
+    pub queue_depth: usize,
+    pub queue_capacity: usize,
+    pub busy_workers: usize,
+    pub workers: Vec<WorkerHealth>,
+}
+
+// Shared liveness flag, cleared when the worker thread exits for any reason including panics
+struct AliveGuard(Arc<AtomicBool>);
+
+impl Drop for AliveGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+// Worker that runs in its own thread, pulling batches from the pool's queue
 struct Worker {
-    sender: Sender<WorkerTask>,
+    control: async_channel::Sender<Control>,
     handle: Option<JoinHandle<()>>,
+    alive: Arc<AtomicBool>,
+}
+
+// Worker whose thread is still loading the plugin
+struct StartingWorker {
+    worker: Worker,
+    ready: Receiver<anyhow::Result<()>>,
+}
+
+impl StartingWorker {
+    /// Waits for the worker's runtime to load the plugin
+    fn ready(self) -> anyhow::Result<Worker> {
+        // Surface plugin load failures to the caller instead of leaving a dead worker behind,
+        // dropping the worker joins its thread
+        match self.ready.recv() {
+            Ok(Ok(())) => Ok(self.worker),
+            Ok(Err(e)) => Err(e),
+            Err(_) => bail!("Worker thread exited before initializing DenoRuntime"),
+        }
+    }
 }
 
 impl Worker {
-    fn new(plugin_path: PathBuf) -> anyhow::Result<Self> {
-        let (sender, receiver) = channel();
+    /// Spawns the worker thread, which loads the plugin without the caller waiting for it
+    fn spawn(
+        index: usize,
+        source: PluginSource,
+        options: PluginOptions,
+        queue: async_channel::Receiver<Job>,
+        busy: Arc<AtomicUsize>,
+    ) -> StartingWorker {
+        let (ready_sender, ready_receiver) = sync_channel(1);
+        let (control, control_receiver) = async_channel::unbounded();
+        let alive = Arc::new(AtomicBool::new(false));
+        let alive_guard = AliveGuard(alive.clone());
 
         let handle = thread::spawn(move || {
+            let _alive_guard = alive_guard;
+
+            if let Some(cores) = &options.worker_cores {
+                affinity::pin_current_thread(cores, index);
+            }
+
+            // Each worker drives its own event loop so plugins can await async ops
+            let tokio_runtime = match tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+            {
+                Ok(rt) => rt,
+                Err(e) => {
+                    let _ = ready_sender.send(Err(
+                        anyhow::Error::from(e).context("Failed to build worker event loop")
+                    ));
+                    return;
+                }
+            };
+
             // Initialize the runtime in the worker thread
-            let mut runtime = match DenoRuntime::new(&plugin_path) {
+            let mut runtime = match source.load(index, &options, &tokio_runtime) {
                 Ok(rt) => rt,
                 Err(e) => {
-                    warn!("Failed to initialize DenoRuntime: {}", e);
+                    let _ = ready_sender.send(Err(e.context("Failed to initialize DenoRuntime")));
                     return;
                 }
             };
 
-            // Process tasks
-            while let Ok(task) = receiver.recv() {
-                match task {
-                    WorkerTask::Execute(values, response_sender) => {
-                        let result = runtime.execute(values);
-                        let _ = response_sender.send(result);
+            _alive_guard.0.store(true, Ordering::SeqCst);
+            let _ = ready_sender.send(Ok(()));
+
+            // Process batches until the pool closes the queue or shuts this worker down
+            loop {
+                let message = tokio_runtime.block_on(async {
+                    tokio::select! {
+                        biased;
+                        control = control_receiver.recv() => control.ok().map(Message::Control),
+                        job = queue.recv() => job.ok().map(Message::Job),
+                    }
+                });
+
+                let job = match message {
+                    Some(Message::Job(job)) => Some(job),
+                    Some(Message::Control(Control::Flush(response))) => {
+                        let _ = response.send(tokio_runtime.block_on(runtime.flush()));
+                        None
+                    }
+                    Some(Message::Control(Control::Shutdown)) | None => break,
+                };
+
+                let mut timed_out = false;
+                if let Some(Job {
+                    values,
+                    guard,
+                    response,
+                }) = job
+                {
+                    if !guard.start(runtime.isolate_handle()) {
+                        continue;
                     }
-                    WorkerTask::Shutdown => break,
+
+                    busy.fetch_add(1, Ordering::SeqCst);
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        tokio_runtime.block_on(runtime.execute(values))
+                    }));
+                    busy.fetch_sub(1, Ordering::SeqCst);
+
+                    // The runtime can't be trusted after a panic, exit so the pool respawns us
+                    let Ok(result) = result else {
+                        error!("Plugin worker panicked while executing batch");
+                        let _ = response.send(Err(anyhow::anyhow!(
+                            "Plugin worker panicked while executing batch"
+                        )));
+                        return;
+                    };
+                    let _ = response.send(result);
+                    timed_out = guard.finish();
+                }
+
+                // A terminated isolate may be left in an inconsistent state, so replace it
+                if timed_out || runtime.needs_restart() {
+                    warn!("Recycling DenoRuntime after terminated JavaScript");
+                    runtime = match source.load(index, &options, &tokio_runtime) {
+                        Ok(rt) => rt,
+                        Err(e) => {
+                            warn!("Failed to initialize DenoRuntime: {}", e);
+                            return;
+                        }
+                    };
                 }
             }
+
+            // Let the plugin release resources before its isolate goes away
+            if let Err(e) = tokio_runtime.block_on(runtime.shutdown()) {
+                warn!("Plugin shutdown hook failed: {e:#}");
+            }
         });
 
-        Ok(Self {
-            sender,
-            handle: Some(handle),
-        })
+        StartingWorker {
+            worker: Self {
+                control,
+                handle: Some(handle),
+                alive,
+            },
+            ready: ready_receiver,
+        }
     }
 
-    fn execute(
-        &self,
-        values: Vec<Value>,
-    ) -> anyhow::Result<oneshot::Receiver<anyhow::Result<TransformResult>>> {
-        let (response_sender, response_receiver) = oneshot::channel();
-        self.sender
-            .send(WorkerTask::Execute(values, response_sender))
-            .context("Failed to send task to worker thread")?;
-        Ok(response_receiver)
+    fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::SeqCst)
     }
 }
 
 impl Drop for Worker {
     fn drop(&mut self) {
-        // Send shutdown signal
-        let _ = self.sender.send(WorkerTask::Shutdown);
+        // Ask the worker to run the plugin's shutdown hook and exit after its current batch
+        let _ = self.control.try_send(Control::Shutdown);
 
         // Wait for the thread to finish
         if let Some(handle) = self.handle.take() {
@@ -146,66 +796,294 @@ impl Drop for Worker {
     }
 }
 
+const INITIAL_RESTART_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(30);
+const SUPERVISE_INTERVAL: Duration = Duration::from_millis(100);
+
+// A worker along with the bookkeeping needed to respawn it with backoff
+struct WorkerSlot {
+    worker: Worker,
+    restarts: u32,
+    backoff: Duration,
+    next_restart: Instant,
+}
+
+// Everything needed to start a worker, shared with the supervisor thread
+struct WorkerFactory {
+    source: PluginSource,
+    options: PluginOptions,
+    queue: async_channel::Receiver<Job>,
+    busy: Arc<AtomicUsize>,
+}
+
+impl WorkerFactory {
+    fn spawn(&self, index: usize) -> StartingWorker {
+        Worker::spawn(
+            index,
+            self.source.clone(),
+            self.options.clone(),
+            self.queue.clone(),
+            self.busy.clone(),
+        )
+    }
+
+    // Respawns dead workers whose backoff has elapsed
+    fn supervise(&self, slots: &Mutex<Vec<WorkerSlot>>) {
+        let dead = {
+            let mut slots = slots.lock().unwrap();
+            let mut dead = Vec::new();
+            for (index, slot) in slots.iter_mut().enumerate() {
+                if slot.worker.is_alive() || Instant::now() < slot.next_restart {
+                    continue;
+                }
+
+                // Back off exponentially so a plugin that keeps crashing doesn't spin, starting
+                // over once a worker has stayed up for a while
+                if Instant::now() > slot.next_restart + MAX_RESTART_BACKOFF {
+                    slot.backoff = INITIAL_RESTART_BACKOFF;
+                }
+                slot.next_restart = Instant::now() + slot.backoff;
+                slot.backoff = (slot.backoff * 2).min(MAX_RESTART_BACKOFF);
+                dead.push(index);
+            }
+            dead
+        };
+        if dead.is_empty() {
+            return;
+        }
+
+        // Load plugins without the lock, so health checks and flushes don't wait on them
+        let starting: Vec<_> = dead.iter().map(|&index| self.spawn(index)).collect();
+        let started: Vec<_> = starting.into_iter().map(StartingWorker::ready).collect();
+
+        let mut replaced = Vec::new();
+        let mut slots = slots.lock().unwrap();
+        for (index, started) in dead.into_iter().zip(started) {
+            match started {
+                Ok(worker) => {
+                    let slot = &mut slots[index];
+                    replaced.push(std::mem::replace(&mut slot.worker, worker));
+                    slot.restarts += 1;
+                    info!(
+                        "Restarted plugin worker {index} ({} restarts)",
+                        slot.restarts
+                    );
+                }
+                Err(e) => warn!("Failed to restart plugin worker {index}: {e:#}"),
+            }
+        }
+
+        // Join the dead workers' threads outside the lock too
+        drop(slots);
+        drop(replaced);
+    }
+}
+
 pub struct DenoPool {
-    workers: Vec<Arc<Mutex<Worker>>>,
-    next_worker: Mutex<usize>,
+    queue: async_channel::Sender<Job>,
+    queue_capacity: usize,
+    slots: Arc<Mutex<Vec<WorkerSlot>>>,
+    busy: Arc<AtomicUsize>,
+    supervisor: Option<(Sender<()>, JoinHandle<()>)>,
+    options: PluginOptions,
+    // Freed after `drop` has joined every worker started from it
+    snapshot: Option<OwnedSnapshot>,
 }
 
 impl DenoPool {
     pub fn new(plugin_path: &Path) -> anyhow::Result<Self> {
-        let worker_count = num_cpus::get();
-        let mut workers = Vec::with_capacity(worker_count);
+        Self::with_options(plugin_path, PluginOptions::default())
+    }
+
+    pub fn with_options(plugin_path: &Path, options: PluginOptions) -> anyhow::Result<Self> {
+        let worker_count = options.worker_count.unwrap_or_else(num_cpus::get).max(1);
+        let queue_capacity = options.queue_capacity.unwrap_or(worker_count * 2).max(1);
+        let (queue, receiver) = async_channel::bounded(queue_capacity);
 
         // On some platforms, deno will SIGSEGV when the runtimes try to start in parallel without
         // first running init_platform
         deno_core::JsRuntime::init_platform(None, false);
 
-        let plugin_path = plugin_path.to_path_buf();
-        for _ in 0..worker_count {
-            // Create a new worker with a cloned path
-            let worker = Worker::new(plugin_path.clone())?;
-            workers.push(Arc::new(Mutex::new(worker)));
+        // Snapshot the plugin once so every worker, including restarts, starts from it. It is
+        // declared before the workers so it outlives them even when startup fails
+        let snapshot = if options.startup_snapshot {
+            Some(OwnedSnapshot::new(
+                create_snapshot(plugin_path, &options.config)
+                    .context("Failed to snapshot plugin")?,
+            ))
+        } else {
+            None
+        };
+
+        let busy = Arc::new(AtomicUsize::new(0));
+        let factory = WorkerFactory {
+            source: PluginSource {
+                path: plugin_path.to_path_buf(),
+                // SAFETY: runtimes only live on worker threads, which `Drop for DenoPool` joins
+                // before the snapshot field is dropped
+                snapshot: snapshot
+                    .as_ref()
+                    .map(|snapshot| unsafe { snapshot.bytes() }),
+            },
+            options: options.clone(),
+            queue: receiver,
+            busy: busy.clone(),
+        };
+
+        // Load the plugin in every worker at once, since startup can take a while per isolate
+        let starting: Vec<_> = (0..worker_count)
+            .map(|index| factory.spawn(index))
+            .collect();
+        let mut slots = Vec::with_capacity(worker_count);
+        for started in starting
+            .into_iter()
+            .map(StartingWorker::ready)
+            .collect::<Vec<_>>()
+        {
+            // Fail fast if the plugin can't be loaded at all
+            let worker = match started {
+                Ok(worker) => worker,
+                Err(e) => {
+                    queue.close();
+                    return Err(e.context("Failed to start plugin worker"));
+                }
+            };
+            slots.push(WorkerSlot {
+                worker,
+                restarts: 0,
+                backoff: INITIAL_RESTART_BACKOFF,
+                next_restart: Instant::now(),
+            });
         }
+        let slots = Arc::new(Mutex::new(slots));
+
+        // Watch for dead workers in the background and respawn them
+        let (stop, stopped) = channel();
+        let supervised = slots.clone();
+        let supervisor = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(SUPERVISE_INTERVAL) {
+                factory.supervise(&supervised);
+            }
+        });
 
         Ok(Self {
-            workers,
-            next_worker: Mutex::new(0),
+            queue,
+            queue_capacity,
+            slots,
+            busy,
+            supervisor: Some((stop, supervisor)),
+            options,
+            snapshot,
         })
     }
 
-    pub async fn execute(&self, values: Vec<Value>) -> anyhow::Result<TransformResult> {
-        // Try to find an available worker first
-        for worker in &self.workers {
-            // Try to lock the worker without blocking
-            if let Ok(worker) = worker.try_lock() {
-                let receiver = worker.execute(values)?;
-                return receiver.await.context("Worker thread panicked")?;
+    /// Reports whether each worker is alive and how many times it has been restarted
+    pub fn health(&self) -> Vec<WorkerHealth> {
+        self.slots
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|slot| WorkerHealth {
+                alive: slot.worker.is_alive(),
+                restarts: slot.restarts,
+            })
+            .collect()
+    }
+
+    /// Reports queue depth and worker utilization
+    pub fn metrics(&self) -> PoolMetrics {
+        PoolMetrics {
+            queue_depth: self.queue.len(),
+            queue_capacity: self.queue_capacity,
+            busy_workers: self.busy.load(Ordering::SeqCst),
+            workers: self.health(),
+        }
+    }
+
+    /// Calls every live worker's `flush()` hook, returning the rows plugins had buffered
+    ///
+    /// Every worker is waited for even when one fails, since the others have already handed
+    /// their rows over.
+    pub async fn flush(&self) -> Flushed {
+        let receivers = {
+            let slots = self.slots.lock().unwrap();
+            slots
+                .iter()
+                .filter(|slot| slot.worker.is_alive())
+                .filter_map(|slot| {
+                    let (response, receiver) = oneshot::channel();
+                    slot.worker
+                        .control
+                        .try_send(Control::Flush(response))
+                        .ok()
+                        .map(|_| receiver)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut flushed = Flushed::default();
+        for receiver in receivers {
+            match receiver.await {
+                Ok(Ok(result)) => flushed.results.extend(result),
+                Ok(Err(e)) => flushed.errors.push(e),
+                Err(_) => flushed
+                    .errors
+                    .push(anyhow::anyhow!("Worker thread exited during flush")),
             }
         }
 
-        // If all workers are busy, fall back to round-robin selection
-        let worker_index = {
-            let mut next = self.next_worker.lock().await;
-            let current = *next;
-            *next = (*next + 1) % self.workers.len();
-            current
+        flushed
+    }
+
+    /// Queues a batch for the next idle worker, waiting for space when the queue is full
+    pub async fn execute(&self, values: Vec<Value>) -> anyhow::Result<TransformResult> {
+        let guard = Arc::new(BatchGuard::new());
+        let (response, mut receiver) = oneshot::channel();
+
+        self.queue
+            .send(Job {
+                values,
+                guard: guard.clone(),
+                response,
+            })
+            .await
+            .map_err(|_| anyhow::anyhow!("Plugin worker queue is closed"))?;
+
+        let Some(timeout) = self.options.batch_timeout else {
+            return receiver.await.context("Worker thread panicked")?;
         };
 
-        // Get the worker and execute the task (this will block until the worker is available)
-        let worker = &self.workers[worker_index];
-        let worker = worker.lock().await;
-        let receiver = worker.execute(values)?;
-        receiver.await.context("Worker thread panicked")?
+        // The deadline starts once a worker picks the batch up, not while it waits in the queue
+        tokio::select! {
+            _ = guard.started.notified() => {}
+            result = &mut receiver => return result.context("Worker thread panicked")?,
+        }
+
+        match tokio::time::timeout(timeout, receiver).await {
+            Ok(result) => result.context("Worker thread panicked")?,
+            Err(_) => {
+                guard.time_out();
+                bail!("Transform batch timed out after {:?}", timeout)
+            }
+        }
     }
 }
 
 impl Drop for DenoPool {
     fn drop(&mut self) {
-        // Workers will be dropped automatically, which will trigger their shutdown
-        // self.workers.clear();
-        while let Some(worker) = self.workers.pop() {
-            drop(worker);
+        // Stop the supervisor so it doesn't respawn workers as they exit
+        if let Some((stop, supervisor)) = self.supervisor.take() {
+            drop(stop);
+            let _ = supervisor.join();
         }
+
+        // Closing the queue lets workers finish their current batch and exit, dropping them
+        // waits for their threads
+        self.queue.close();
+        self.slots.lock().unwrap().clear();
+
+        // The workers have joined, so nothing uses the snapshot anymore
+        self.snapshot.take();
     }
 }
 
@@ -215,6 +1093,108 @@ pub struct TransformResult {
     pub table_info: Option<TableInfo>,
     pub data: Option<Vec<Value>>,
     pub error: Option<String>,
+    /// Optional per-input outcomes, linked back to the batch by input index
+    #[serde(default)]
+    pub outcomes: Vec<InputOutcome>,
+    /// Rows for further tables, so one batch can fan out to several tables
+    #[serde(default)]
+    pub tables: Vec<TableGroup>,
+}
+
+impl TransformResult {
+    /// Rows for the top-level `table_info`: `data` followed by the rows of every `ok` outcome
+    pub fn rows(&self) -> impl Iterator<Item = &Value> {
+        self.indexed_rows().map(|(_, row)| row)
+    }
+
+    /// Rows like [`rows`](Self::rows), with the index of the input each outcome row came from
+    pub fn indexed_rows(&self) -> impl Iterator<Item = (Option<usize>, &Value)> {
+        let outcome_rows = self.outcomes.iter().flat_map(|outcome| {
+            let rows = match &outcome.status {
+                OutcomeStatus::Ok { rows } => rows.as_slice(),
+                _ => &[],
+            };
+            rows.iter().map(move |row| (Some(outcome.index), row))
+        });
+
+        self.data
+            .iter()
+            .flatten()
+            .map(|row| (None, row))
+            .chain(outcome_rows)
+    }
+
+    /// Inputs the plugin reported as failed, with their index, error and original input
+    pub fn failures(&self) -> impl Iterator<Item = (usize, &str, Option<&Value>)> {
+        self.outcomes
+            .iter()
+            .filter_map(|outcome| match &outcome.status {
+                OutcomeStatus::Failed { error, input } => {
+                    Some((outcome.index, error.as_str(), input.as_ref()))
+                }
+                _ => None,
+            })
+    }
+
+    // Attaches the original input to failed outcomes so it can be handled on its own
+    fn attach_failed_inputs(&mut self, inputs: &[Value]) {
+        for outcome in &mut self.outcomes {
+            if let OutcomeStatus::Failed { input, .. } = &mut outcome.status {
+                match inputs.get(outcome.index) {
+                    Some(value) => *input = Some(value.clone()),
+                    None => warn!(
+                        "Plugin reported an outcome for input {} of a batch of {}",
+                        outcome.index,
+                        inputs.len()
+                    ),
+                }
+            }
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct TableGroup {
+    pub table_info: TableInfo,
+    pub rows: Vec<Value>,
+    /// Optional index of the input each row came from, one per row
+    #[serde(default)]
+    pub inputs: Vec<usize>,
+}
+
+impl TableGroup {
+    /// Rows with the index of the input each came from, if the plugin reported it
+    pub fn indexed_rows(&self) -> impl Iterator<Item = (Option<usize>, &Value)> {
+        self.rows
+            .iter()
+            .enumerate()
+            .map(|(position, row)| (self.inputs.get(position).copied(), row))
+    }
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct InputOutcome {
+    pub index: usize,
+    #[serde(flatten)]
+    pub status: OutcomeStatus,
+}
+
+#[derive(serde::Deserialize, Debug)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum OutcomeStatus {
+    Ok {
+        #[serde(default)]
+        rows: Vec<Value>,
+    },
+    Skipped {
+        reason: String,
+    },
+    Failed {
+        error: String,
+        /// Filled in from the batch by the runtime, plugins don't need to echo it
+        #[serde(skip)]
+        input: Option<Value>,
+    },
 }
 
 #[derive(serde::Deserialize, Debug)]
@@ -222,11 +1202,84 @@ pub struct TableInfo {
     pub name: String,
     pub schema: String,
     pub columns: Vec<Column>,
+    /// Column or columns identifying a row, the default conflict target for upserts
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub primary_key: Vec<String>,
+    /// Conflict target to use instead of the primary key, e.g. a unique constraint's columns
+    #[serde(default)]
+    pub conflict_columns: Vec<String>,
+    /// What to do when a row conflicts, defaults to `update_all` when there's a conflict target
+    #[serde(default)]
+    pub on_conflict: Option<ConflictPolicy>,
+    /// How rows are written, defaults to the pool's row-count threshold
+    #[serde(default)]
+    pub load_method: Option<LoadMethod>,
 }
 
-#[derive(serde::Deserialize, Debug)]
+impl TableInfo {
+    /// Columns used in the `ON CONFLICT` target
+    pub fn conflict_target(&self) -> &[String] {
+        if self.conflict_columns.is_empty() {
+            &self.primary_key
+        } else {
+            &self.conflict_columns
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadMethod {
+    /// `INSERT ... SELECT * FROM UNNEST(...)`
+    Insert,
+    /// Binary `COPY ... FROM STDIN`
+    Copy,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// Keep the existing row
+    DoNothing,
+    /// Overwrite every column outside the conflict target
+    UpdateAll,
+    /// Overwrite only the listed columns
+    Update { columns: Vec<String> },
+    /// Overwrite every column outside the conflict target if the incoming version is newer
+    UpdateIfNewer { version_column: String },
+}
+
+// Accepts `"id"` as shorthand for `["id"]`
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match serde::Deserialize::deserialize(deserializer)? {
+        OneOrMany::One(column) => vec![column],
+        OneOrMany::Many(columns) => columns,
+    })
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
 pub struct Column {
     pub name: String,
     #[serde(alias = "type")]
     pub r#type: String,
+    /// Whether missing keys and `null`s are inserted as NULL rather than rejected
+    #[serde(default = "default_nullable")]
+    pub nullable: bool,
+    /// Value used when a row doesn't have this column
+    #[serde(default)]
+    pub default: Option<Value>,
+}
+
+fn default_nullable() -> bool {
+    true
 }
@@ -11,10 +11,13 @@ use std::path::Path;
 use std::time::Duration;
 use tokio::pin;
 use tokio_stream::wrappers::ReceiverStream;
-use tracing::info;
+use tracing::{info, warn};
 
-use crate::postgres::{self, insert_data};
-use crate::{aimd_stream, deno};
+use crate::aimd_stream;
+use crate::commit_group::CommitGroup;
+use crate::deno::FlushSchedule;
+use crate::postgres::{self, Inserted};
+use crate::reload::ReloadablePool;
 
 type MessageStreamResult = Result<(String, DynamicMessage)>;
 
@@ -22,7 +25,7 @@ type MessageStreamResult = Result<(String, DynamicMessage)>;
 pub async fn process_file(
     file_path: &Path,
     type_name: &str,
-    plugin: &Path,
+    js_pool: &ReloadablePool,
     pg_pool: &postgres::Pool,
 ) -> Result<usize> {
     info!("Processing protobuf messages from file: {:?}", file_path);
@@ -32,8 +35,6 @@ pub async fn process_file(
         .map(|_| tokio::sync::mpsc::channel::<DynamicMessage>(1000))
         .unzip();
 
-    let js_pool = deno::DenoPool::new(plugin)?;
-
     let file_path = file_path.to_owned();
     let type_name = type_name.to_owned();
     tokio::spawn(async move {
@@ -73,22 +74,91 @@ pub async fn process_file(
             })
             .and_then(|values| async {
                 let res = js_pool.execute(values).await?;
+                for (index, error, input) in res.failures() {
+                    warn!("Plugin failed to transform input {index}: {error}, input: {input:?}");
+                }
                 anyhow::Ok(res)
             });
 
         Box::pin(x)
     });
 
-    let stream = futures::stream::select_all(rx_streams);
+    let transformed = futures::stream::select_all(rx_streams);
+
+    // Interleave plugin flushes with transformed batches so buffered rows are inserted too
+    let batches = async_stream::try_stream! {
+        pin!(transformed);
+        let mut schedule = FlushSchedule::new(js_pool.options());
+
+        loop {
+            let (batch, mut flush) = tokio::select! {
+                batch = transformed.next() => match batch {
+                    Some(batch) => (Some(batch), false),
+                    None => break,
+                },
+                _ = schedule.tick() => (None, true),
+            };
+
+            if let Some(batch) = batch {
+                yield batch?;
+                flush = schedule.record_batch();
+            }
 
-    let inserted = stream
-        .and_then(|batch| async move { insert_data(pg_pool, &batch).await })
-        .try_fold(0, |acc, inserted| async move { Ok(acc + inserted) })
-        .await?;
+            if flush {
+                schedule.reset();
+                let flushed = js_pool.flush().await;
+                for result in flushed.results {
+                    yield result;
+                }
+                flush_errors(flushed.errors)?;
+            }
+        }
+
+        // Give plugins a last chance to emit buffered rows before the run ends
+        let flushed = js_pool.flush().await;
+        for result in flushed.results {
+            yield result;
+        }
+        flush_errors(flushed.errors)?;
+    };
+
+    // Rows only count once the transaction they were written in commits
+    pin!(batches);
+    let mut commit_group = CommitGroup::new(pg_pool);
+    let mut inserted = 0;
+    while let Some(batch) = batches.next().await {
+        for ((), settled) in commit_group.write(pg_pool, (), batch?).await {
+            inserted += settled_rows(settled)?;
+        }
+    }
+    for ((), settled) in commit_group.commit(pg_pool).await {
+        inserted += settled_rows(settled)?;
+    }
 
     Ok(inserted as usize)
 }
 
+/// Fails the run on a plugin flush error, once the rows other workers flushed are written
+fn flush_errors(errors: Vec<anyhow::Error>) -> Result<()> {
+    let mut errors = errors.into_iter();
+    let Some(first) = errors.next() else {
+        return Ok(());
+    };
+    for e in errors {
+        warn!("Failed to flush plugin: {e:#}");
+    }
+    Err(first.context("Failed to flush plugin"))
+}
+
+/// Counts the rows a batch inserted, reporting the rows Postgres rejected so the run goes on
+fn settled_rows(settled: Result<Inserted>) -> Result<u64> {
+    let inserted = settled?;
+    for rejected in &inserted.rejected {
+        warn!("Postgres rejected {rejected}, row: {}", rejected.row);
+    }
+    Ok(inserted.rows)
+}
+
 /// Reads the descriptor pool and messages from a zstandard compressed file
 pub fn read_pool_and_messages(
     path: &Path,
@@ -1,5 +1,7 @@
+use crate::commit_group::{CommitGroup, Settled};
 use crate::config::AppConfig;
-use crate::deno::DenoRuntime;
+use crate::deno::{FlushSchedule, TransformResult};
+use crate::reload::ReloadablePool;
 use crate::{postgres, protobuf};
 use anyhow::{Context, Result};
 use rdkafka::client::ClientContext;
@@ -9,8 +11,12 @@ use rdkafka::consumer::{
 };
 use rdkafka::error::KafkaResult;
 use rdkafka::message::Message;
+use rdkafka::{Offset, TopicPartitionList};
 use schema_registry_converter::async_impl::schema_registry::SrSettings;
 use schema_registry_converter::schema_registry_common::SubjectNameStrategy;
+use std::collections::HashMap;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::time::{Instant, sleep_until};
 use tracing::{error, info, warn};
 
 struct CustomContext;
@@ -36,7 +42,7 @@ impl ConsumerContext for CustomContext {
 
 type LoggingConsumer = StreamConsumer<CustomContext>;
 
-pub async fn consume_messages(config: AppConfig, mut plugin: DenoRuntime) -> Result<()> {
+pub async fn consume_messages(config: AppConfig, plugin: &ReloadablePool) -> Result<()> {
     // Create Schema Registry client
     let sr_settings = SrSettings::new(config.schema_registry_url.clone());
 
@@ -56,44 +62,76 @@ pub async fn consume_messages(config: AppConfig, mut plugin: DenoRuntime) -> Res
 
     info!("Subscribed to topic: {}", config.topic);
 
-    // Process messages
+    let mut flush_schedule = FlushSchedule::new(plugin.options());
+    let mut commit_group = CommitGroup::new(&config.pg_pool);
+    // Next offset of each partition, committed once no earlier message waits on a database commit
+    let mut offsets = HashMap::new();
+
+    let mut interrupt = signal(SignalKind::interrupt()).context("Failed to listen for SIGINT")?;
+    let mut terminate = signal(SignalKind::terminate()).context("Failed to listen for SIGTERM")?;
+
+    // Process messages until asked to stop
     loop {
-        match consumer.recv().await {
+        let deadline = commit_group.deadline();
+        let received = tokio::select! {
+            received = consumer.recv() => received,
+            _ = interrupt.recv() => break,
+            _ = terminate.recv() => break,
+            _ = flush_schedule.tick() => {
+                flush_plugin(plugin, &config.pg_pool).await;
+                flush_schedule.reset();
+                continue;
+            }
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                log_settled(commit_group.commit(&config.pg_pool).await);
+                commit_offsets(&consumer, &config.topic, &mut offsets, CommitMode::Async);
+                continue;
+            }
+        };
+
+        match received {
             Ok(msg) => {
-                let payload = match msg.payload() {
-                    Some(p) => p,
-                    None => {
-                        warn!("Empty message received");
-                        consumer.commit_message(&msg, CommitMode::Async).unwrap();
-                        continue;
-                    }
-                };
-
-                let key = String::from_utf8(msg.key().unwrap_or(&[]).to_vec())?;
-
-                // Process the message
-                match process_message(
-                    key,
-                    payload,
-                    &sr_settings,
-                    &mut plugin,
-                    &config.pg_pool,
-                    &config.topic,
-                )
-                .await
-                {
-                    Ok(_) => {
-                        info!("Message processed successfully");
-                        consumer.commit_message(&msg, CommitMode::Async).unwrap();
-                    }
-                    Err(e) => {
-                        error!("Failed to process message: {}", e);
-                        // Depending on your error handling strategy, you might want to:
-                        // - Skip the message and continue
-                        // - Retry a certain number of times
-                        // - Stop processing
-                        consumer.commit_message(&msg, CommitMode::Async).unwrap();
+                match msg.payload() {
+                    Some(payload) => {
+                        let key = String::from_utf8(msg.key().unwrap_or(&[]).to_vec())?;
+
+                        // Process the message
+                        match transform_message(key, payload, &sr_settings, plugin, &config.topic)
+                            .await
+                        {
+                            Ok(transformed) => {
+                                // The message is the only input, so a failed outcome means it failed
+                                if let Some((_, error, _)) = transformed.failures().next() {
+                                    error!(
+                                        "JavaScript plugin failed to transform message: {error}"
+                                    );
+                                }
+
+                                let tag = (msg.partition(), msg.offset());
+                                let settled =
+                                    commit_group.write(&config.pg_pool, tag, transformed).await;
+                                log_settled(settled);
+                            }
+                            Err(e) => {
+                                error!("Failed to process message: {}", e);
+                                // Depending on your error handling strategy, you might want to:
+                                // - Skip the message and continue
+                                // - Retry a certain number of times
+                                // - Stop processing
+                            }
+                        }
                     }
+                    None => warn!("Empty message received"),
+                }
+
+                offsets.insert(msg.partition(), msg.offset() + 1);
+                if commit_group.is_empty() {
+                    commit_offsets(&consumer, &config.topic, &mut offsets, CommitMode::Async);
+                }
+
+                if flush_schedule.record_batch() {
+                    flush_plugin(plugin, &config.pg_pool).await;
+                    flush_schedule.reset();
                 }
             }
             Err(e) => {
@@ -101,16 +139,89 @@ pub async fn consume_messages(config: AppConfig, mut plugin: DenoRuntime) -> Res
             }
         }
     }
+
+    // Insert what plugins still buffer and settle open transactions before the pools are dropped
+    info!("Shutting down, flushing plugin and committing pending writes");
+    flush_plugin(plugin, &config.pg_pool).await;
+    log_settled(commit_group.commit(&config.pg_pool).await);
+    commit_offsets(&consumer, &config.topic, &mut offsets, CommitMode::Sync);
+
+    Ok(())
+}
+
+/// Logs the outcome of messages whose rows were committed or failed to insert
+fn log_settled(settled: Settled<(i32, i64)>) {
+    for ((partition, offset), inserted) in settled {
+        match inserted {
+            Ok(inserted) => {
+                for rejected in &inserted.rejected {
+                    error!(
+                        "Postgres rejected {rejected} of message at partition {partition} offset {offset}, row: {}",
+                        rejected.row
+                    );
+                }
+                info!("Message processed successfully");
+            }
+            Err(e) => error!(
+                "Failed to insert message at partition {partition} offset {offset} into PostgreSQL: {e:#}"
+            ),
+        }
+    }
 }
 
-async fn process_message(
+/// Commits the offsets of processed messages, whose rows are all committed or given up on
+fn commit_offsets(
+    consumer: &LoggingConsumer,
+    topic: &str,
+    offsets: &mut HashMap<i32, i64>,
+    mode: CommitMode,
+) {
+    if offsets.is_empty() {
+        return;
+    }
+
+    let mut list = TopicPartitionList::new();
+    for (partition, offset) in offsets.drain() {
+        if let Err(e) = list.add_partition_offset(topic, partition, Offset::Offset(offset)) {
+            error!("Failed to add offset {offset} of partition {partition}: {e}");
+        }
+    }
+
+    if let Err(e) = consumer.commit(&list, mode) {
+        error!("Failed to commit offsets: {e}");
+    }
+}
+
+/// Inserts rows the plugin had buffered, logging failures since their offsets are already committed
+async fn flush_plugin(plugin: &ReloadablePool, pg_pool: &postgres::Pool) {
+    let flushed = plugin.flush().await;
+    for e in &flushed.errors {
+        error!("Failed to flush plugin: {e:#}");
+    }
+
+    for result in flushed.results {
+        match postgres::insert_data(pg_pool, &result).await {
+            Ok(inserted) => {
+                for rejected in &inserted.rejected {
+                    error!(
+                        "Postgres rejected flushed plugin {rejected}, row: {}",
+                        rejected.row
+                    );
+                }
+            }
+            Err(e) => error!("Failed to insert flushed plugin rows: {e:#}"),
+        }
+    }
+}
+
+/// Decodes a message and transforms it with the JavaScript plugin
+async fn transform_message(
     key: String,
     payload: &[u8],
     sr_settings: &SrSettings,
-    plugin: &mut DenoRuntime,
-    pg_pool: &postgres::Pool,
+    plugin: &ReloadablePool,
     topic: &str,
-) -> Result<()> {
+) -> Result<TransformResult> {
     // Get schema from Schema Registry
     let subject_name_strategy = SubjectNameStrategy::TopicNameStrategy(topic.to_string(), false);
 
@@ -119,14 +230,9 @@ async fn process_message(
         .await
         .context("Failed to decode Protobuf message")?;
 
-    // // Transform the message using the JavaScript plugin
-    // let transformed = crate::deno::transform_message(plugin, &key, &decoded)
-    //     .context("Failed to transform message with JavaScript plugin")?;
-    //
-    // // Insert into PostgreSQL
-    // crate::postgres::insert_data(pg_client, &transformed)
-    //     .await
-    //     .context("Failed to insert data into PostgreSQL")?;
-
-    Ok(())
+    // Transform the message using the JavaScript plugin
+    plugin
+        .execute(vec![decoded])
+        .await
+        .context("Failed to transform message with JavaScript plugin")
 }
@@ -1,10 +1,18 @@
+pub mod affinity;
 mod aimd_stream;
+pub mod commit_group;
 pub mod config;
 pub mod deno;
 pub mod file;
 pub mod kafka;
+pub mod migrate;
+pub mod pg_tls;
+pub mod pg_value;
 pub mod postgres;
 pub mod protobuf;
+pub mod reload;
+pub mod schema;
+pub mod statement_cache;
 
 // Re-export main components for easier testing
 pub use config::AppConfig;
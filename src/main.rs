@@ -1,11 +1,17 @@
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use clap::Parser;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use tracing::{info, level_filters::LevelFilter};
 use tracing_subscriber::{EnvFilter, Layer, layer::SubscriberExt, util::SubscriberInitExt};
 
 // Use the modules from lib.rs instead of defining them here
-use kafka_postgres_transform::{config::AppConfig, deno, file, kafka, postgres};
+use kafka_postgres_transform::pg_tls::{SslMode, TlsOptions};
+use kafka_postgres_transform::reload::{self, ReloadablePool};
+use kafka_postgres_transform::{affinity, config, config::AppConfig, deno, file, kafka, postgres};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -14,6 +20,26 @@ struct Args {
     #[arg(short = 'j', long)]
     plugin: PathBuf,
 
+    #[command(flatten)]
+    plugin_args: PluginArgs,
+
+    /// Pin each plugin worker thread to its own core
+    #[arg(long)]
+    pin_workers: bool,
+
+    /// Pin the async runtime threads to the cores not used by plugin workers
+    #[arg(long)]
+    pin_runtime: bool,
+
+    /// Comma separated list of core ids available for pinning (defaults to all cores), used
+    /// with --pin-workers or --pin-runtime
+    #[arg(long, value_delimiter = ',')]
+    cores: Option<Vec<usize>>,
+
+    /// Reload the plugin whenever its file changes (SIGHUP always triggers a reload)
+    #[arg(long)]
+    watch_plugin: bool,
+
     /// PostgreSQL connection string
     #[arg(
         short,
@@ -22,10 +48,160 @@ struct Args {
     )]
     postgres_url: String,
 
+    /// TLS mode for Postgres: disable, prefer, require, verify-ca or verify-full (overrides sslmode in the URL)
+    #[arg(long)]
+    ssl_mode: Option<SslMode>,
+
+    /// PEM file of the CA certificates to trust for Postgres (overrides sslrootcert in the URL)
+    #[arg(long)]
+    ssl_root_cert: Option<PathBuf>,
+
+    /// PEM file of the client certificate for Postgres (overrides sslcert in the URL)
+    #[arg(long)]
+    ssl_cert: Option<PathBuf>,
+
+    /// PEM file of the client's PKCS#8 private key for Postgres (overrides sslkey in the URL)
+    #[arg(long)]
+    ssl_key: Option<PathBuf>,
+
+    /// Insert all tables of a transform result in one transaction
+    #[arg(long)]
+    group_transactions: bool,
+
+    /// Load tables with binary COPY once a batch has at least this many rows for them
+    #[arg(long)]
+    copy_threshold: Option<usize>,
+
+    /// Comma separated schema.table names results may write to (defaults to any table)
+    #[arg(long, value_delimiter = ',')]
+    allowed_tables: Vec<String>,
+
+    /// Create missing target tables and columns from the plugin's table_info
+    #[arg(long)]
+    auto_migrate: bool,
+
+    /// Maximum number of Postgres connections
+    #[arg(long, default_value_t = 16)]
+    pool_max_size: usize,
+
+    /// How Postgres connections are checked before reuse: fast, verified or clean
+    #[arg(long, default_value = "fast", value_parser = postgres::parse_recycling_method)]
+    pool_recycling: deadpool_postgres::RecyclingMethod,
+
+    /// Maximum time in milliseconds to wait for a free Postgres connection
+    #[arg(long)]
+    pool_wait_timeout_ms: Option<u64>,
+
+    /// Maximum time in milliseconds to open a new Postgres connection
+    #[arg(long)]
+    pool_create_timeout_ms: Option<u64>,
+
+    /// Maximum time in milliseconds to check a Postgres connection before reuse
+    #[arg(long)]
+    pool_recycle_timeout_ms: Option<u64>,
+
+    /// Session setting applied to each new Postgres connection as name=value (repeatable)
+    #[arg(long = "pg-set", value_parser = postgres::parse_session_setting)]
+    session_settings: Vec<(String, String)>,
+
+    /// Maximum number of prepared statements kept on each Postgres connection
+    #[arg(long, default_value_t = postgres::DEFAULT_STATEMENT_CACHE_SIZE)]
+    statement_cache_size: NonZeroUsize,
+
+    /// Commit grouped writes in one transaction after this many batches
+    #[arg(long)]
+    commit_every_batches: Option<usize>,
+
+    /// Commit grouped writes in one transaction at least this often, every second by default
+    /// when only --commit-every-batches is given
+    #[arg(long)]
+    commit_interval_ms: Option<u64>,
+
     #[command(subcommand)]
     command: Command,
 }
 
+impl Args {
+    fn pool_options(&self) -> postgres::PoolOptions {
+        postgres::PoolOptions {
+            tls: TlsOptions {
+                mode: self.ssl_mode,
+                root_cert: self.ssl_root_cert.clone(),
+                client_cert: self.ssl_cert.clone(),
+                client_key: self.ssl_key.clone(),
+            },
+            max_size: Some(self.pool_max_size),
+            recycling_method: self.pool_recycling.clone(),
+            wait_timeout: self.pool_wait_timeout_ms.map(Duration::from_millis),
+            create_timeout: self.pool_create_timeout_ms.map(Duration::from_millis),
+            recycle_timeout: self.pool_recycle_timeout_ms.map(Duration::from_millis),
+            session_settings: self.session_settings.clone(),
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct PluginArgs {
+    /// Number of plugin worker threads (defaults to the number of CPUs)
+    #[arg(long)]
+    workers: Option<usize>,
+
+    /// Maximum number of batches waiting for a plugin worker (defaults to twice the worker count)
+    #[arg(long)]
+    queue_capacity: Option<usize>,
+
+    /// Maximum time in milliseconds a plugin may spend on one batch or lifecycle hook
+    #[arg(long)]
+    batch_timeout_ms: Option<u64>,
+
+    /// Initial V8 heap size in megabytes for each plugin worker
+    #[arg(long, default_value_t = 0)]
+    initial_heap_size_mb: usize,
+
+    /// Maximum V8 heap size in megabytes for each plugin worker
+    #[arg(long)]
+    max_heap_size_mb: Option<usize>,
+
+    /// Evaluate the plugin in every worker instead of starting workers from a V8 snapshot
+    #[arg(long)]
+    no_snapshot: bool,
+
+    /// JSON or TOML file exposed to the plugin as globalThis.config and passed to init(config)
+    #[arg(long)]
+    plugin_config: Option<PathBuf>,
+
+    /// Environment variables exposed to the plugin under config.env (comma separated)
+    #[arg(long, value_delimiter = ',')]
+    plugin_env: Vec<String>,
+
+    /// Call the plugin's flush() hook at least this often, in milliseconds
+    #[arg(long)]
+    flush_interval_ms: Option<u64>,
+
+    /// Call the plugin's flush() hook after this many batches
+    #[arg(long)]
+    flush_every_batches: Option<usize>,
+}
+
+impl PluginArgs {
+    fn to_options(&self) -> Result<deno::PluginOptions> {
+        let config = config::load_plugin_config(self.plugin_config.as_deref(), &self.plugin_env)?;
+
+        Ok(deno::PluginOptions {
+            worker_count: self.workers,
+            queue_capacity: self.queue_capacity,
+            batch_timeout: self.batch_timeout_ms.map(Duration::from_millis),
+            initial_heap_size: self.initial_heap_size_mb * 1024 * 1024,
+            max_heap_size: self.max_heap_size_mb.map(|mb| mb * 1024 * 1024),
+            worker_cores: None,
+            startup_snapshot: !self.no_snapshot,
+            config,
+            flush_interval: self.flush_interval_ms.map(Duration::from_millis),
+            flush_every_batches: self.flush_every_batches,
+        })
+    }
+}
+
 #[derive(clap::Subcommand, Debug)]
 enum Command {
     /// Process messages from Kafka
@@ -70,15 +246,60 @@ fn setup_tracing() {
         .init();
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     // Initialize logging
     setup_tracing();
 
     // Parse command line arguments
     let args = Args::parse();
 
-    let pg_pool = postgres::Pool::new(&args.postgres_url)?;
+    if args.cores.is_some() && !args.pin_workers && !args.pin_runtime {
+        bail!("--cores only applies with --pin-workers or --pin-runtime");
+    }
+
+    // Decide which cores plugin workers and runtime threads are pinned to
+    let cores = affinity::available_cores(args.cores.as_deref());
+    let worker_count = args.plugin_args.workers.unwrap_or_else(num_cpus::get);
+    let (worker_cores, runtime_cores) = affinity::split_cores(&cores, worker_count);
+
+    let mut plugin_options = args.plugin_args.to_options()?;
+    if args.pin_workers {
+        plugin_options.worker_cores = Some(worker_cores);
+    }
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if args.pin_runtime && !runtime_cores.is_empty() {
+        let next_thread = AtomicUsize::new(0);
+        runtime
+            .worker_threads(runtime_cores.len())
+            .on_thread_start(move || {
+                let index = next_thread.fetch_add(1, Ordering::SeqCst);
+                affinity::pin_current_thread(&runtime_cores, index);
+            });
+    }
+
+    runtime
+        .build()
+        .context("Failed to build async runtime")?
+        .block_on(run(args, plugin_options))
+}
+
+async fn run(args: Args, plugin_options: deno::PluginOptions) -> Result<()> {
+    let pg_pool = postgres::Pool::new(&args.postgres_url, &args.pool_options())?
+        .with_group_transactions(args.group_transactions)
+        .with_copy_threshold(args.copy_threshold)
+        .with_allowed_tables(&args.allowed_tables)
+        .with_auto_migrate(args.auto_migrate)
+        .with_statement_cache_size(args.statement_cache_size)
+        .with_commit_grouping(
+            args.commit_every_batches,
+            args.commit_interval_ms.map(Duration::from_millis),
+        );
+    pg_pool.prefetch_allowed_tables().await?;
+
+    let js_pool = Arc::new(ReloadablePool::new(&args.plugin, plugin_options)?);
+    let _reloader = reload::spawn_reloader(js_pool.clone(), args.watch_plugin)?;
 
     match &args.command {
         Command::Kafka {
@@ -98,8 +319,7 @@ async fn main() -> Result<()> {
 
             // Start Kafka consumer
             info!("Starting Kafka consumer for topic: {}", config.topic);
-            let plugin = deno::DenoRuntime::new(&args.plugin)?;
-            kafka::consume_messages(config, plugin)
+            kafka::consume_messages(config, &js_pool)
                 .await
                 .context("Error in Kafka message consumption")?;
         }
@@ -107,7 +327,7 @@ async fn main() -> Result<()> {
         Command::File { input, type_name } => {
             // Process messages from file
             info!("Processing messages from file: {:?}", input);
-            let count = file::process_file(input, type_name, &args.plugin, &pg_pool)
+            let count = file::process_file(input, type_name, &js_pool, &pg_pool)
                 .await
                 .context("Error processing file");
 
@@ -0,0 +1,163 @@
+//! Opt-in creation and evolution of target tables from the `table_info` plugins emit
+use anyhow::{Context, Result};
+use tracing::info;
+
+use crate::deno::{Column, TableInfo};
+use crate::pg_value::ColumnType;
+use crate::postgres::{qualified_table, quote_ident, quote_idents};
+
+/// Every DDL statement run by auto-migration is recorded here
+pub const MIGRATIONS_TABLE: &str = "public.kafka_postgres_transform_migrations";
+
+// Advisory lock serializing migrations across workers and processes ("kpt_mig" in ASCII)
+const MIGRATION_LOCK: i64 = 0x006b_7074_5f6d_6967;
+
+/// Creates the table if it doesn't exist, or adds any columns it is missing
+///
+/// Columns are only ever added, never altered or dropped. Added columns are nullable even if
+/// declared otherwise, since existing rows have no value for them.
+pub async fn ensure_table(
+    client: &mut deadpool_postgres::Client,
+    table_info: &TableInfo,
+) -> Result<()> {
+    let table = qualified_table(table_info)?;
+    let transaction = client
+        .transaction()
+        .await
+        .context("Failed to start migration transaction")?;
+
+    transaction
+        .execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK])
+        .await
+        .context("Failed to take migration lock")?;
+
+    transaction
+        .batch_execute(&format!(
+            "CREATE TABLE IF NOT EXISTS {MIGRATIONS_TABLE} (
+                id bigserial PRIMARY KEY,
+                applied_at timestamptz NOT NULL DEFAULT now(),
+                schema_name text NOT NULL,
+                table_name text NOT NULL,
+                statement text NOT NULL
+            )"
+        ))
+        .await
+        .context("Failed to create migrations table")?;
+
+    let existing: Vec<String> = transaction
+        .query(
+            "SELECT column_name::text FROM information_schema.columns \
+             WHERE table_schema = $1 AND table_name = $2",
+            &[&table_info.schema, &table_info.name],
+        )
+        .await
+        .with_context(|| format!("Failed to read columns of {table}"))?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+
+    let mut statements = Vec::new();
+
+    if existing.is_empty() {
+        let schema_exists = transaction
+            .query_opt(
+                "SELECT 1 FROM pg_namespace WHERE nspname = $1",
+                &[&table_info.schema],
+            )
+            .await
+            .context("Failed to look up schema")?
+            .is_some();
+
+        if !schema_exists {
+            statements.push(format!(
+                "CREATE SCHEMA IF NOT EXISTS {}",
+                quote_ident(&table_info.schema)?
+            ));
+        }
+        statements.push(create_table_statement(table_info)?);
+    } else {
+        for col in &table_info.columns {
+            if !existing.contains(&col.name) {
+                statements.push(format!(
+                    "ALTER TABLE {table} ADD COLUMN IF NOT EXISTS {} {}",
+                    quote_ident(&col.name)?,
+                    column_type(col)?
+                ));
+            }
+        }
+    }
+
+    for statement in &statements {
+        info!("Migrating {table}: {statement}");
+        transaction
+            .batch_execute(statement)
+            .await
+            .with_context(|| format!("Failed to migrate {table} with {statement}"))?;
+        transaction
+            .execute(
+                format!(
+                    "INSERT INTO {MIGRATIONS_TABLE} (schema_name, table_name, statement) \
+                     VALUES ($1, $2, $3)"
+                )
+                .as_str(),
+                &[&table_info.schema, &table_info.name, statement],
+            )
+            .await
+            .context("Failed to record migration")?;
+    }
+
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit migration")?;
+
+    Ok(())
+}
+
+/// Builds the `CREATE TABLE IF NOT EXISTS` statement for a table the plugin declares
+pub fn create_table_statement(table_info: &TableInfo) -> Result<String> {
+    let mut definitions = table_info
+        .columns
+        .iter()
+        .map(|col| {
+            let not_null = if col.nullable { "" } else { " NOT NULL" };
+            Ok(format!(
+                "{} {}{not_null}",
+                quote_ident(&col.name)?,
+                column_type(col)?
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if !table_info.primary_key.is_empty() {
+        definitions.push(format!(
+            "PRIMARY KEY ({})",
+            quote_idents(table_info.primary_key.iter().map(String::as_str))?
+        ));
+    }
+
+    // ON CONFLICT needs a unique index on the conflict target
+    if !table_info.conflict_columns.is_empty()
+        && table_info.conflict_columns != table_info.primary_key
+    {
+        definitions.push(format!(
+            "UNIQUE ({})",
+            quote_idents(table_info.conflict_columns.iter().map(String::as_str))?
+        ));
+    }
+
+    Ok(format!(
+        "CREATE TABLE IF NOT EXISTS {} ({})",
+        qualified_table(table_info)?,
+        definitions.join(", ")
+    ))
+}
+
+/// Maps a declared column type to the Postgres type it is created with
+pub fn column_type(col: &Column) -> Result<String> {
+    match ColumnType::parse(&col.r#type) {
+        Some(ColumnType::Scalar(scalar)) => Ok(scalar.pg_type().to_string()),
+        Some(ColumnType::Array(element)) => Ok(format!("{}[]", element.pg_type())),
+        None => anyhow::bail!("Unsupported type '{}' for column {}", col.r#type, col.name),
+    }
+}
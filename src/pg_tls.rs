@@ -0,0 +1,248 @@
+//! TLS for Postgres connections, configured with libpq's `sslmode`, `sslrootcert`, `sslcert`
+//! and `sslkey` parameters
+use anyhow::{Context, Result, bail};
+use native_tls::{Certificate, Identity, TlsConnector};
+use postgres_native_tls::MakeTlsConnector;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// libpq's `sslmode`, of which tokio-postgres only understands the first three
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SslMode {
+    Disable,
+    Prefer,
+    Require,
+    VerifyCa,
+    VerifyFull,
+}
+
+impl FromStr for SslMode {
+    type Err = anyhow::Error;
+
+    fn from_str(mode: &str) -> Result<Self> {
+        match mode {
+            "disable" => Ok(SslMode::Disable),
+            "prefer" => Ok(SslMode::Prefer),
+            "require" => Ok(SslMode::Require),
+            "verify-ca" => Ok(SslMode::VerifyCa),
+            "verify-full" => Ok(SslMode::VerifyFull),
+            _ => bail!(
+                "Invalid sslmode '{mode}', expected disable, prefer, require, verify-ca or verify-full"
+            ),
+        }
+    }
+}
+
+/// TLS settings of a connection, each of which can come from the URL or a CLI flag
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TlsOptions {
+    pub mode: Option<SslMode>,
+    /// PEM file of the CA certificates trusted instead of the system's
+    pub root_cert: Option<PathBuf>,
+    /// PEM file of the client certificate, presented along with `client_key`
+    pub client_cert: Option<PathBuf>,
+    /// PEM file of the client's PKCS#8 private key
+    pub client_key: Option<PathBuf>,
+}
+
+impl TlsOptions {
+    /// Returns these options with every field set in `overrides` replaced
+    pub fn with_overrides(self, overrides: &TlsOptions) -> Self {
+        Self {
+            mode: overrides.mode.or(self.mode),
+            root_cert: overrides.root_cert.clone().or(self.root_cert),
+            client_cert: overrides.client_cert.clone().or(self.client_cert),
+            client_key: overrides.client_key.clone().or(self.client_key),
+        }
+    }
+
+    /// Builds the connector for these settings, or `None` when TLS is disabled
+    pub fn connector(&self) -> Result<Option<MakeTlsConnector>> {
+        // Like libpq, connections try TLS unless told otherwise
+        let mode = self.mode.unwrap_or(SslMode::Prefer);
+        if mode == SslMode::Disable {
+            return Ok(None);
+        }
+
+        let mut builder = TlsConnector::builder();
+
+        if let Some(path) = &self.root_cert {
+            builder.disable_built_in_roots(true);
+            for certificate in read_certificates(path)? {
+                builder.add_root_certificate(certificate);
+            }
+        }
+
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                let identity =
+                    Identity::from_pkcs8(&read(cert)?, &read(key)?).with_context(|| {
+                        format!(
+                            "Failed to load client certificate {} with key {}",
+                            cert.display(),
+                            key.display()
+                        )
+                    })?;
+                builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => bail!("sslcert and sslkey must be given together"),
+        }
+
+        // As in libpq, require verifies the certificate chain only when given a root certificate
+        let verify_chain = match mode {
+            SslMode::Disable | SslMode::Prefer => false,
+            SslMode::Require => self.root_cert.is_some(),
+            SslMode::VerifyCa | SslMode::VerifyFull => true,
+        };
+        builder.danger_accept_invalid_certs(!verify_chain);
+        builder.danger_accept_invalid_hostnames(mode != SslMode::VerifyFull);
+
+        let connector = builder.build().context("Failed to set up TLS")?;
+        Ok(Some(MakeTlsConnector::new(connector)))
+    }
+
+    fn pg_ssl_mode(&self) -> tokio_postgres::config::SslMode {
+        match self.mode {
+            Some(SslMode::Disable) => tokio_postgres::config::SslMode::Disable,
+            Some(SslMode::Prefer) | None => tokio_postgres::config::SslMode::Prefer,
+            Some(SslMode::Require | SslMode::VerifyCa | SslMode::VerifyFull) => {
+                tokio_postgres::config::SslMode::Require
+            }
+        }
+    }
+
+    // Takes a TLS parameter, returning false for any other key
+    fn set(&mut self, key: &str, value: &str) -> Result<bool> {
+        match key {
+            "sslmode" => self.mode = Some(value.parse()?),
+            "sslrootcert" => self.root_cert = Some(value.into()),
+            "sslcert" => self.client_cert = Some(value.into()),
+            "sslkey" => self.client_key = Some(value.into()),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+/// Parses a connection string and builds its TLS connector
+///
+/// `overrides` take precedence over the TLS parameters of the connection string.
+pub fn configure(
+    url: &str,
+    overrides: &TlsOptions,
+) -> Result<(tokio_postgres::Config, Option<MakeTlsConnector>)> {
+    let (url, tls) = split_tls_params(url)?;
+    let tls = tls.with_overrides(overrides);
+
+    let mut config: tokio_postgres::Config = url
+        .parse()
+        .context("Invalid PostgreSQL connection string")?;
+    config.ssl_mode(tls.pg_ssl_mode());
+
+    Ok((config, tls.connector()?))
+}
+
+/// Takes the TLS parameters out of a URL or key/value connection string
+///
+/// tokio-postgres rejects all of them but `sslmode`, and only some of its values.
+pub fn split_tls_params(url: &str) -> Result<(String, TlsOptions)> {
+    let mut tls = TlsOptions::default();
+
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        let Some((base, query)) = url.split_once('?') else {
+            return Ok((url.to_string(), tls));
+        };
+
+        let mut kept = Vec::new();
+        for param in query.split('&') {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            let value = percent_encoding::percent_decode_str(value)
+                .decode_utf8()
+                .with_context(|| format!("Invalid value for {key} in connection string"))?;
+            if !tls.set(key, &value)? {
+                kept.push(param);
+            }
+        }
+
+        let url = if kept.is_empty() {
+            base.to_string()
+        } else {
+            format!("{base}?{}", kept.join("&"))
+        };
+        return Ok((url, tls));
+    }
+
+    let mut kept = Vec::new();
+    for (key, raw, value) in key_value_params(url)? {
+        if !tls.set(key, &value)? {
+            kept.push(format!("{key}={raw}"));
+        }
+    }
+
+    Ok((kept.join(" "), tls))
+}
+
+// Splits a key/value connection string into keys, raw values and unquoted values
+fn key_value_params(s: &str) -> Result<Vec<(&str, &str, String)>> {
+    let mut params = Vec::new();
+    let mut rest = s.trim_start();
+
+    while !rest.is_empty() {
+        let Some((key, after)) = rest.split_once('=') else {
+            bail!("Missing value for '{rest}' in connection string");
+        };
+        let key = key.trim();
+        let after = after.trim_start();
+
+        let quoted = after.starts_with('\'');
+        let mut chars = after.char_indices().skip(usize::from(quoted));
+        let mut value = String::new();
+        let end = loop {
+            match chars.next() {
+                Some((_, '\\')) => value.extend(chars.next().map(|(_, c)| c)),
+                Some((i, '\'')) if quoted => break i + 1,
+                Some((i, c)) if !quoted && c.is_whitespace() => break i,
+                Some((_, c)) => value.push(c),
+                None if quoted => bail!("Unterminated quoted value for {key} in connection string"),
+                None => break after.len(),
+            }
+        };
+
+        params.push((key, &after[..end], value));
+        rest = after[end..].trim_start();
+    }
+
+    Ok(params)
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))
+}
+
+// A CA bundle holds several certificates, which native-tls has to be given one at a time
+fn read_certificates(path: &Path) -> Result<Vec<Certificate>> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+
+    let pem = String::from_utf8(read(path)?)
+        .with_context(|| format!("{} is not a PEM file", path.display()))?;
+
+    let certificates = pem
+        .match_indices(BEGIN)
+        .map(|(start, _)| {
+            let end = pem[start..]
+                .find(END)
+                .map(|end| start + end + END.len())
+                .with_context(|| format!("Unterminated certificate in {}", path.display()))?;
+            Certificate::from_pem(&pem.as_bytes()[start..end])
+                .with_context(|| format!("Invalid certificate in {}", path.display()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if certificates.is_empty() {
+        bail!("No certificates found in {}", path.display());
+    }
+
+    Ok(certificates)
+}
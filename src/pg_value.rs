@@ -0,0 +1,528 @@
+//! Coercion of JSON values produced by plugins into Postgres column types
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use bytes::BytesMut;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use serde_json::Value;
+use std::error::Error;
+use std::net::IpAddr;
+use tokio_postgres::types::{IsNull, ToSql, Type, accepts, to_sql_checked};
+use uuid::Uuid;
+
+/// A declared column type: a scalar, or a one-dimensional array of one (`"int[]"`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColumnType {
+    Scalar(ScalarType),
+    Array(ScalarType),
+}
+
+impl ColumnType {
+    pub fn parse(declared: &str) -> Option<Self> {
+        match declared.trim().strip_suffix("[]") {
+            Some(element) => ScalarType::parse(element).map(ColumnType::Array),
+            None => ScalarType::parse(declared).map(ColumnType::Scalar),
+        }
+    }
+
+    /// Maps a `udt_name` from `information_schema.columns`, where arrays are prefixed with `_`
+    pub fn from_udt_name(udt_name: &str) -> Option<Self> {
+        let scalar = |name: &str| match name {
+            "bpchar" => Some(ScalarType::Text),
+            name => ScalarType::parse(name),
+        };
+
+        match udt_name.strip_prefix('_') {
+            Some(element) => scalar(element).map(ColumnType::Array),
+            None => scalar(udt_name).map(ColumnType::Scalar),
+        }
+    }
+}
+
+impl std::fmt::Display for ColumnType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ColumnType::Scalar(scalar) => f.write_str(scalar.pg_type()),
+            ColumnType::Array(element) => write!(f, "{}[]", element.pg_type()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScalarType {
+    SmallInt,
+    Int,
+    BigInt,
+    Numeric,
+    Float,
+    Bool,
+    Text,
+    Timestamp,
+    TimestampTz,
+    Date,
+    Time,
+    Interval,
+    Uuid,
+    Json,
+    Jsonb,
+    Bytea,
+    Inet,
+}
+
+impl ScalarType {
+    pub fn parse(declared: &str) -> Option<Self> {
+        let scalar = match declared.trim().to_ascii_lowercase().as_str() {
+            "smallint" | "int2" => ScalarType::SmallInt,
+            "int" | "integer" | "int4" => ScalarType::Int,
+            "bigint" | "int8" => ScalarType::BigInt,
+            "numeric" | "decimal" => ScalarType::Numeric,
+            "float" | "float8" | "double" => ScalarType::Float,
+            "bool" | "boolean" => ScalarType::Bool,
+            "string" | "text" | "varchar" => ScalarType::Text,
+            "timestamp" => ScalarType::Timestamp,
+            "timestamptz" => ScalarType::TimestampTz,
+            "date" => ScalarType::Date,
+            "time" => ScalarType::Time,
+            "interval" => ScalarType::Interval,
+            "uuid" => ScalarType::Uuid,
+            "json" => ScalarType::Json,
+            "jsonb" => ScalarType::Jsonb,
+            "bytea" => ScalarType::Bytea,
+            "inet" => ScalarType::Inet,
+            _ => return None,
+        };
+
+        Some(scalar)
+    }
+
+    pub fn pg_type(&self) -> &'static str {
+        match self {
+            ScalarType::SmallInt => "int2",
+            ScalarType::Int => "int4",
+            ScalarType::BigInt => "int8",
+            ScalarType::Numeric => "numeric",
+            ScalarType::Float => "float8",
+            ScalarType::Bool => "bool",
+            ScalarType::Text => "text",
+            ScalarType::Timestamp => "timestamp",
+            ScalarType::TimestampTz => "timestamptz",
+            ScalarType::Date => "date",
+            ScalarType::Time => "time",
+            ScalarType::Interval => "interval",
+            ScalarType::Uuid => "uuid",
+            ScalarType::Json => "json",
+            ScalarType::Jsonb => "jsonb",
+            ScalarType::Bytea => "bytea",
+            ScalarType::Inet => "inet",
+        }
+    }
+
+    pub fn sql_type(&self) -> Type {
+        match self {
+            ScalarType::SmallInt => Type::INT2,
+            ScalarType::Int => Type::INT4,
+            ScalarType::BigInt => Type::INT8,
+            ScalarType::Numeric => Type::NUMERIC,
+            ScalarType::Float => Type::FLOAT8,
+            ScalarType::Bool => Type::BOOL,
+            ScalarType::Text => Type::TEXT,
+            ScalarType::Timestamp => Type::TIMESTAMP,
+            ScalarType::TimestampTz => Type::TIMESTAMPTZ,
+            ScalarType::Date => Type::DATE,
+            ScalarType::Time => Type::TIME,
+            ScalarType::Interval => Type::INTERVAL,
+            ScalarType::Uuid => Type::UUID,
+            ScalarType::Json => Type::JSON,
+            ScalarType::Jsonb => Type::JSONB,
+            ScalarType::Bytea => Type::BYTEA,
+            ScalarType::Inet => Type::INET,
+        }
+    }
+
+    /// Converts a value and renders it in Postgres' text input format, for array literals
+    pub fn to_text(self, value: &Value) -> Option<String> {
+        match self {
+            ScalarType::SmallInt => to_i16(value).map(|v| v.to_string()),
+            ScalarType::Int => to_i32(value).map(|v| v.to_string()),
+            ScalarType::BigInt => to_i64(value).map(|v| v.to_string()),
+            ScalarType::Numeric => to_numeric(value).map(|v| v.to_string()),
+            ScalarType::Float => to_f64(value).map(|v| v.to_string()),
+            ScalarType::Bool => value.as_bool().map(|v| v.to_string()),
+            ScalarType::Text => to_string(value),
+            ScalarType::Timestamp => to_timestamp(value).map(|v| v.to_string()),
+            ScalarType::TimestampTz => to_timestamptz(value).map(|v| v.to_rfc3339()),
+            ScalarType::Date => to_date(value).map(|v| v.to_string()),
+            ScalarType::Time => to_time(value).map(|v| v.to_string()),
+            ScalarType::Interval => to_interval(value).map(|v| v.to_string()),
+            ScalarType::Uuid => to_uuid(value).map(|v| v.to_string()),
+            ScalarType::Json | ScalarType::Jsonb => Some(value.to_string()),
+            ScalarType::Bytea => to_bytea(value).map(|bytes| {
+                let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+                format!("\\x{hex}")
+            }),
+            ScalarType::Inet => to_inet(value).map(|v| v.to_string()),
+        }
+    }
+}
+
+/// Renders a JSON array as a Postgres array literal, e.g. `{"1","2",NULL}`
+pub fn to_array_literal(element: ScalarType, value: &Value) -> Option<String> {
+    let elements = value
+        .as_array()?
+        .iter()
+        .map(|element_value| match element_value {
+            Value::Null => Some("NULL".to_string()),
+            element_value => element.to_text(element_value).map(|text| {
+                let escaped = text.replace('\\', "\\\\").replace('"', "\\\"");
+                format!("\"{escaped}\"")
+            }),
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(format!("{{{}}}", elements.join(",")))
+}
+
+pub fn to_i16(value: &Value) -> Option<i16> {
+    to_i64(value).and_then(|v| i16::try_from(v).ok())
+}
+
+pub fn to_i32(value: &Value) -> Option<i32> {
+    to_i64(value).and_then(|v| i32::try_from(v).ok())
+}
+
+/// Integers, or strings of digits since protobuf's JSON mapping encodes 64-bit integers as strings
+pub fn to_i64(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(n) => n.as_i64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Numbers or numeric strings, strings keep their exact precision
+pub fn to_numeric(value: &Value) -> Option<Numeric> {
+    match value {
+        Value::Number(n) => Numeric::parse(&n.to_string()),
+        Value::String(s) => Numeric::parse(s),
+        _ => None,
+    }
+}
+
+pub fn to_f64(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        _ => None,
+    }
+}
+
+pub fn to_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+/// `YYYY-MM-DDTHH:MM:SS[.f]` (or with a space), or Unix epoch milliseconds taken as UTC
+pub fn to_timestamp(value: &Value) -> Option<NaiveDateTime> {
+    match value {
+        Value::String(s) => NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f")
+            .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f"))
+            .ok(),
+        Value::Number(_) => to_timestamptz(value).map(|v| v.naive_utc()),
+        _ => None,
+    }
+}
+
+/// RFC 3339 strings, or Unix epoch milliseconds
+pub fn to_timestamptz(value: &Value) -> Option<DateTime<Utc>> {
+    match value {
+        Value::String(s) => DateTime::parse_from_rfc3339(s)
+            .ok()
+            .map(|v| v.with_timezone(&Utc)),
+        Value::Number(n) => n.as_i64().and_then(DateTime::from_timestamp_millis),
+        _ => None,
+    }
+}
+
+/// `YYYY-MM-DD`
+pub fn to_date(value: &Value) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(value.as_str()?, "%Y-%m-%d").ok()
+}
+
+/// `HH:MM[:SS[.f]]`
+pub fn to_time(value: &Value) -> Option<NaiveTime> {
+    let s = value.as_str()?;
+    NaiveTime::parse_from_str(s, "%H:%M:%S%.f")
+        .or_else(|_| NaiveTime::parse_from_str(s, "%H:%M"))
+        .ok()
+}
+
+/// ISO 8601 durations such as `P1DT2H30M`, or a number of seconds
+pub fn to_interval(value: &Value) -> Option<Interval> {
+    match value {
+        Value::String(s) => Interval::parse_iso8601(s),
+        Value::Number(n) => n.as_f64().map(Interval::from_seconds),
+        _ => None,
+    }
+}
+
+pub fn to_uuid(value: &Value) -> Option<Uuid> {
+    Uuid::parse_str(value.as_str()?).ok()
+}
+
+/// Standard base64 with padding
+pub fn to_bytea(value: &Value) -> Option<Vec<u8>> {
+    BASE64.decode(value.as_str()?).ok()
+}
+
+/// IPv4 or IPv6 addresses, without a netmask
+pub fn to_inet(value: &Value) -> Option<IpAddr> {
+    value.as_str()?.parse().ok()
+}
+
+/// Postgres `interval`, which keeps months and days apart from the time of day
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Interval {
+    pub months: i32,
+    pub days: i32,
+    pub microseconds: i64,
+}
+
+impl Interval {
+    pub fn from_seconds(seconds: f64) -> Self {
+        Self {
+            microseconds: (seconds * 1_000_000.0).round() as i64,
+            ..Default::default()
+        }
+    }
+
+    /// Parses `[-]P[nY][nM][nW][nD][T[nH][nM][nS]]`, where only seconds may be fractional
+    pub fn parse_iso8601(s: &str) -> Option<Self> {
+        let (sign, rest) = match s.strip_prefix('-') {
+            Some(rest) => (-1, rest),
+            None => (1, s),
+        };
+        let rest = rest.strip_prefix('P')?;
+        if rest.is_empty() {
+            return None;
+        }
+
+        let mut interval = Interval::default();
+        let mut in_time = false;
+        let mut number = String::new();
+
+        for c in rest.chars() {
+            match c {
+                'T' if !in_time && number.is_empty() => in_time = true,
+                '0'..='9' | '.' => number.push(c),
+                unit => {
+                    let amount = std::mem::take(&mut number);
+                    let scaled = |scale: i32| amount.parse::<i32>().ok()?.checked_mul(scale);
+                    let micros = |scale: i64| amount.parse::<i64>().ok()?.checked_mul(scale);
+
+                    // Amounts come from plugins, so overflow is a bad value rather than a bug
+                    match (in_time, unit) {
+                        (false, 'Y') => {
+                            interval.months = interval.months.checked_add(scaled(12)?)?
+                        }
+                        (false, 'M') => {
+                            interval.months = interval.months.checked_add(scaled(1)?)?
+                        }
+                        (false, 'W') => interval.days = interval.days.checked_add(scaled(7)?)?,
+                        (false, 'D') => interval.days = interval.days.checked_add(scaled(1)?)?,
+                        (true, 'H') => {
+                            interval.microseconds =
+                                interval.microseconds.checked_add(micros(3_600_000_000)?)?
+                        }
+                        (true, 'M') => {
+                            interval.microseconds =
+                                interval.microseconds.checked_add(micros(60_000_000)?)?
+                        }
+                        (true, 'S') => {
+                            let seconds = amount.parse::<f64>().ok()?;
+                            let microseconds = (seconds * 1_000_000.0).round();
+                            if !microseconds.is_finite() || microseconds.abs() >= i64::MAX as f64 {
+                                return None;
+                            }
+                            interval.microseconds =
+                                interval.microseconds.checked_add(microseconds as i64)?;
+                        }
+                        _ => return None,
+                    }
+                }
+            }
+        }
+
+        if !number.is_empty() {
+            return None;
+        }
+
+        Some(Interval {
+            months: sign * interval.months,
+            days: sign * interval.days,
+            microseconds: i64::from(sign) * interval.microseconds,
+        })
+    }
+}
+
+impl std::fmt::Display for Interval {
+    /// Formats in Postgres' own interval input syntax
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} mons {} days {} microseconds",
+            self.months, self.days, self.microseconds
+        )
+    }
+}
+
+impl ToSql for Interval {
+    fn to_sql(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        out.extend_from_slice(&self.microseconds.to_be_bytes());
+        out.extend_from_slice(&self.days.to_be_bytes());
+        out.extend_from_slice(&self.months.to_be_bytes());
+        Ok(IsNull::No)
+    }
+
+    accepts!(INTERVAL);
+    to_sql_checked!();
+}
+
+/// Postgres `numeric`, kept in its base-10000 wire format so any precision is written exactly
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Numeric {
+    text: String,
+    sign: u16,
+    weight: i16,
+    scale: u16,
+    digits: Vec<i16>,
+}
+
+const NUMERIC_POSITIVE: u16 = 0x0000;
+const NUMERIC_NEGATIVE: u16 = 0x4000;
+const NUMERIC_NAN: u16 = 0xC000;
+const NUMERIC_INFINITY: u16 = 0xD000;
+const NUMERIC_NEGATIVE_INFINITY: u16 = 0xF000;
+/// Most digits after the decimal point Postgres keeps (NUMERIC_DSCALE_MAX)
+const NUMERIC_MAX_SCALE: i64 = 0x3FFF;
+
+impl Numeric {
+    /// Parses Postgres' numeric input syntax: `[+-]digits[.digits][e[+-]digits]`, `NaN` and
+    /// `[+-]Infinity`
+    pub fn parse(s: &str) -> Option<Self> {
+        let text = s.trim();
+        let (negative, unsigned) = match text.as_bytes().first()? {
+            b'-' => (true, &text[1..]),
+            b'+' => (false, &text[1..]),
+            _ => (false, text),
+        };
+
+        let special = |sign| Self {
+            text: text.to_string(),
+            sign,
+            weight: 0,
+            scale: 0,
+            digits: Vec::new(),
+        };
+        if unsigned.eq_ignore_ascii_case("nan") && unsigned.len() == text.len() {
+            return Some(special(NUMERIC_NAN));
+        }
+        if unsigned.eq_ignore_ascii_case("infinity") || unsigned.eq_ignore_ascii_case("inf") {
+            return Some(special(if negative {
+                NUMERIC_NEGATIVE_INFINITY
+            } else {
+                NUMERIC_INFINITY
+            }));
+        }
+
+        let (mantissa, exponent) = match unsigned.split_once(['e', 'E']) {
+            Some((mantissa, exponent)) => (mantissa, exponent.parse::<i32>().ok()?),
+            None => (unsigned, 0),
+        };
+        let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        let is_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+        if integer.is_empty() && fraction.is_empty() || !is_digits(integer) || !is_digits(fraction)
+        {
+            return None;
+        }
+
+        // Every digit is placed by its power of ten, the first one's being `point - 1`
+        let digits = format!("{integer}{fraction}");
+        let point = integer.len() as i64 + i64::from(exponent);
+        let scale = (digits.len() as i64 - point).max(0);
+        if scale > NUMERIC_MAX_SCALE {
+            return None;
+        }
+
+        let significant = digits.trim_start_matches('0');
+        let point = point - (digits.len() - significant.len()) as i64;
+        let significant = significant.trim_end_matches('0');
+        if significant.is_empty() {
+            return Some(Self {
+                text: text.to_string(),
+                sign: NUMERIC_POSITIVE,
+                weight: 0,
+                scale: scale as u16,
+                digits: Vec::new(),
+            });
+        }
+
+        // Digits are grouped four to a base-10000 digit, aligned on the decimal point
+        let weight = (point - 1).div_euclid(4);
+        let lowest = (point - significant.len() as i64).div_euclid(4);
+        let groups = usize::try_from(weight - lowest + 1).ok()?;
+        if i16::try_from(weight).is_err() || i16::try_from(groups).is_err() {
+            return None;
+        }
+
+        let mut base_digits = vec![0i16; groups];
+        for (i, digit) in significant.bytes().enumerate() {
+            let power = point - 1 - i as i64;
+            let group = (weight - power.div_euclid(4)) as usize;
+            base_digits[group] += i16::from(digit - b'0') * 10i16.pow(power.rem_euclid(4) as u32);
+        }
+
+        Some(Self {
+            text: text.to_string(),
+            sign: if negative {
+                NUMERIC_NEGATIVE
+            } else {
+                NUMERIC_POSITIVE
+            },
+            weight: weight as i16,
+            scale: scale as u16,
+            digits: base_digits,
+        })
+    }
+}
+
+impl std::fmt::Display for Numeric {
+    /// Formats as the value was given, which Postgres parses back to the same number
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl ToSql for Numeric {
+    fn to_sql(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        out.extend_from_slice(&(self.digits.len() as i16).to_be_bytes());
+        out.extend_from_slice(&self.weight.to_be_bytes());
+        out.extend_from_slice(&self.sign.to_be_bytes());
+        out.extend_from_slice(&self.scale.to_be_bytes());
+        for digit in &self.digits {
+            out.extend_from_slice(&digit.to_be_bytes());
+        }
+        Ok(IsNull::No)
+    }
+
+    accepts!(NUMERIC);
+    to_sql_checked!();
+}
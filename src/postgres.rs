@@ -1,194 +1,1102 @@
 use anyhow::{Context, Result, bail};
-use dashmap::{DashMap, Entry};
-use deadpool_postgres::Manager;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use dashmap::{DashMap, DashSet};
+use deadpool_postgres::{GenericClient, Hook, HookError, Manager, RecyclingMethod, Runtime};
 use serde_json::Value;
-use tokio_postgres::{Client, NoTls, Statement, types::ToSql};
-use tracing::{info, warn};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::pin;
+use tokio_postgres::binary_copy::BinaryCopyInWriter;
+use tokio_postgres::config::Host;
+use tokio_postgres::error::DbError;
+use tokio_postgres::types::{ToSql, Type};
+use tokio_postgres::{Client, NoTls};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
 
-use crate::deno::TransformResult;
+use crate::deno::{Column, ConflictPolicy, LoadMethod, TableInfo, TransformResult};
+use crate::migrate;
+use crate::pg_tls::{self, TlsOptions};
+use crate::pg_value::{self, ColumnType, Interval, ScalarType};
+use crate::schema::{self, DbColumn};
+use crate::statement_cache::{PooledClient, StatementCache};
+
+/// Prepared statements kept per connection unless configured otherwise
+pub const DEFAULT_STATEMENT_CACHE_SIZE: NonZeroUsize = NonZeroUsize::new(100).unwrap();
+
+/// How long grouped batches wait to commit when only a batch count is given
+pub const DEFAULT_COMMIT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Connection pool settings, applied when the pool is created
+#[derive(Clone, Debug, Default)]
+pub struct PoolOptions {
+    /// TLS settings overriding those of the connection string
+    pub tls: TlsOptions,
+    /// Maximum number of connections, defaults to 16
+    pub max_size: Option<usize>,
+    /// How connections are checked before being reused
+    pub recycling_method: RecyclingMethod,
+    /// Maximum time to wait for a free connection
+    pub wait_timeout: Option<Duration>,
+    /// Maximum time to open a new connection
+    pub create_timeout: Option<Duration>,
+    /// Maximum time to check a connection before reusing it
+    pub recycle_timeout: Option<Duration>,
+    /// Session settings, such as `statement_timeout`, applied to each new connection
+    pub session_settings: Vec<(String, String)>,
+}
 
 pub struct Pool {
     db: deadpool::managed::Pool<Manager>,
-    query_cache: DashMap<(String, String, String, String), Statement>,
+    statements: StatementCache,
+    group_transactions: bool,
+    copy_threshold: Option<usize>,
+    allowed_tables: HashSet<(String, String)>,
+    auto_migrate: bool,
+    // Table layouts already migrated, keyed by schema, name and column names
+    migrated: DashSet<(String, String, Vec<String>)>,
+    // Live column definitions of target tables, keyed by schema and name
+    table_columns: DashMap<(String, String), Arc<Vec<DbColumn>>>,
+    commit_every_batches: Option<usize>,
+    commit_interval: Option<Duration>,
 }
 
 impl Pool {
-    pub fn new(url: &str) -> anyhow::Result<Self> {
-        let pg_config: tokio_postgres::Config = url.parse()?;
+    pub fn new(url: &str, options: &PoolOptions) -> anyhow::Result<Self> {
+        let (pg_config, tls) = pg_tls::configure(url, &options.tls)?;
         let mgr_config = deadpool_postgres::ManagerConfig {
-            recycling_method: deadpool_postgres::RecyclingMethod::Fast,
+            recycling_method: options.recycling_method.clone(),
+        };
+        let mgr = match tls {
+            Some(tls) => deadpool_postgres::Manager::from_config(pg_config, tls, mgr_config),
+            None => deadpool_postgres::Manager::from_config(pg_config, NoTls, mgr_config),
         };
-        let mgr =
-            deadpool_postgres::Manager::from_config(pg_config, tokio_postgres::NoTls, mgr_config);
-        let pg_pool = deadpool_postgres::Pool::builder(mgr)
-            .max_size(16)
+
+        let mut builder = deadpool_postgres::Pool::builder(mgr)
+            .max_size(options.max_size.unwrap_or(16))
+            .wait_timeout(options.wait_timeout)
+            .create_timeout(options.create_timeout)
+            .recycle_timeout(options.recycle_timeout)
+            .runtime(Runtime::Tokio1);
+
+        if !options.session_settings.is_empty() {
+            builder = builder.post_create(session_hook(options.session_settings.clone()));
+            // Clean recycling runs RESET ALL, which undoes the settings
+            if options.recycling_method == RecyclingMethod::Clean {
+                builder = builder.post_recycle(session_hook(options.session_settings.clone()));
+            }
+        }
+
+        let pg_pool = builder
             .build()
-            .unwrap();
+            .context("Failed to build PostgreSQL connection pool")?;
 
         Ok(Self {
             db: pg_pool,
-            query_cache: DashMap::new(),
+            statements: StatementCache::new(DEFAULT_STATEMENT_CACHE_SIZE),
+            group_transactions: false,
+            copy_threshold: None,
+            allowed_tables: HashSet::new(),
+            auto_migrate: false,
+            migrated: DashSet::new(),
+            table_columns: DashMap::new(),
+            commit_every_batches: None,
+            commit_interval: None,
         })
     }
+
+    /// Inserts all table groups of a result in one transaction instead of independently
+    pub fn with_group_transactions(mut self, enabled: bool) -> Self {
+        self.group_transactions = enabled;
+        self
+    }
+
+    /// Loads groups of at least this many rows with binary `COPY` unless their table says otherwise
+    pub fn with_copy_threshold(mut self, rows: Option<usize>) -> Self {
+        self.copy_threshold = rows;
+        self
+    }
+
+    /// Only accepts results for these tables, given as `schema.table` or `table` in `public`
+    ///
+    /// An empty list allows every table.
+    pub fn with_allowed_tables(mut self, tables: &[String]) -> Self {
+        self.allowed_tables = tables
+            .iter()
+            .map(|table| match table.split_once('.') {
+                Some((schema, name)) => (schema.to_string(), name.to_string()),
+                None => ("public".to_string(), table.clone()),
+            })
+            .collect();
+        self
+    }
+
+    /// Caps the number of prepared statements kept on each connection
+    pub fn with_statement_cache_size(mut self, size: NonZeroUsize) -> Self {
+        self.statements = StatementCache::new(size);
+        self
+    }
+
+    /// Creates missing tables and columns from `table_info` before the first insert into them
+    pub fn with_auto_migrate(mut self, enabled: bool) -> Self {
+        self.auto_migrate = enabled;
+        self
+    }
+
+    /// Writes the results of several batches in one transaction per [`CommitGroup`]
+    ///
+    /// A group commits after `batches` results or once its transaction has been open for
+    /// `interval`, whichever comes first. Without either, every result commits on its own.
+    /// With only `batches`, the interval defaults to [`DEFAULT_COMMIT_INTERVAL`], so a
+    /// transaction doesn't stay open while no more batches arrive.
+    ///
+    /// [`CommitGroup`]: crate::commit_group::CommitGroup
+    pub fn with_commit_grouping(
+        mut self,
+        batches: Option<usize>,
+        interval: Option<Duration>,
+    ) -> Self {
+        self.commit_every_batches = batches;
+        self.commit_interval = interval.or(batches.map(|_| DEFAULT_COMMIT_INTERVAL));
+        self
+    }
+
+    pub(crate) fn commit_grouping(&self) -> (Option<usize>, Option<Duration>) {
+        (self.commit_every_batches, self.commit_interval)
+    }
+
+    pub(crate) async fn connection(&self) -> Result<deadpool_postgres::Client> {
+        self.db
+            .get()
+            .await
+            .context("Failed to get a PostgreSQL connection")
+    }
+
+    /// Reads the columns of every allowed table, so a missing one fails at startup rather than
+    /// on its first batch
+    ///
+    /// With auto-migration a missing table is created by its first batch, so it's only logged.
+    pub async fn prefetch_allowed_tables(&self) -> Result<()> {
+        if self.allowed_tables.is_empty() {
+            return Ok(());
+        }
+
+        let client = self.connection().await?;
+        for (schema, name) in &self.allowed_tables {
+            match schema::fetch_columns(&client, schema, name).await {
+                Ok(columns) => {
+                    self.table_columns
+                        .insert((schema.clone(), name.clone()), Arc::new(columns));
+                }
+                Err(e) if self.auto_migrate => info!("{e:#}, its first batch will create it"),
+                Err(e) => return Err(e.context("Failed to check allowed tables")),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether writing the result would run a migration, which commits its own transaction
+    pub(crate) fn needs_migration(&self, data: &TransformResult) -> bool {
+        let top_level = data
+            .table_info
+            .iter()
+            .filter(|_| data.rows().next().is_some());
+        let groups = data
+            .tables
+            .iter()
+            .filter(|group| !group.rows.is_empty())
+            .map(|group| &group.table_info);
+
+        // Like `prepare_tables`, tables without rows aren't migrated
+        self.auto_migrate
+            && top_level
+                .chain(groups)
+                .any(|table_info| !self.migrated.contains(&migration_key(table_info)))
+    }
+
+    async fn migrate(
+        &self,
+        client: &mut deadpool_postgres::Client,
+        table_info: &TableInfo,
+    ) -> Result<()> {
+        let key = migration_key(table_info);
+
+        if !self.auto_migrate || self.migrated.contains(&key) {
+            return Ok(());
+        }
+
+        migrate::ensure_table(client, table_info).await?;
+        self.statements.clear();
+        self.table_columns
+            .remove(&(table_info.schema.clone(), table_info.name.clone()));
+        self.migrated.insert(key);
+        Ok(())
+    }
+
+    /// Checks a table's declared columns against the database, which is queried once per table
+    ///
+    /// Also returns whether the table's type of every column is known, so values are sent in
+    /// exactly that type.
+    async fn resolve_columns(
+        &self,
+        client: &impl GenericClient,
+        table_info: &TableInfo,
+    ) -> Result<(Vec<Column>, bool)> {
+        let key = (table_info.schema.clone(), table_info.name.clone());
+
+        let cached = self.table_columns.get(&key).map(|columns| columns.clone());
+        let db_columns = match cached {
+            Some(columns) => columns,
+            None => {
+                let columns = schema::fetch_columns(client, &table_info.schema, &table_info.name);
+                let columns = Arc::new(columns.await?);
+                self.table_columns.insert(key, columns.clone());
+                columns
+            }
+        };
+
+        let columns = schema::resolve_columns(table_info, &db_columns)?;
+        let types_known = columns.iter().all(|col| {
+            db_columns
+                .iter()
+                .any(|db| db.name == col.name && db.column_type.is_some())
+        });
+
+        Ok((columns, types_known))
+    }
+
+    fn check_allowed(&self, table_info: &TableInfo) -> Result<()> {
+        if self.allowed_tables.is_empty()
+            || self
+                .allowed_tables
+                .contains(&(table_info.schema.clone(), table_info.name.clone()))
+        {
+            return Ok(());
+        }
+
+        bail!(
+            "Table {}.{} is not in the list of allowed tables",
+            table_info.schema,
+            table_info.name
+        );
+    }
+
+    // COPY can't send array columns, which need UNNEST's text literal casts, or columns of
+    // types it doesn't know
+    fn use_copy(&self, table: &TableWrite<'_>) -> bool {
+        let TableWrite {
+            table_info,
+            column_data,
+            rows,
+            types_known,
+            ..
+        } = table;
+
+        let copy = match table_info.load_method {
+            Some(LoadMethod::Copy) => true,
+            Some(LoadMethod::Insert) => false,
+            None => self
+                .copy_threshold
+                .is_some_and(|threshold| rows.len() >= threshold),
+        };
+
+        if copy && column_data.iter().any(|c| c.sql_type().is_none()) {
+            debug!(
+                "Inserting into {}.{} with UNNEST because it has array columns",
+                table_info.schema, table_info.name
+            );
+            return false;
+        }
+
+        // Binary COPY sends values in the declared type's wire format, which Postgres rejects
+        // for any other column type, where UNNEST would cast
+        if copy && !types_known {
+            debug!(
+                "Inserting into {}.{} with UNNEST because some of its column types are unknown",
+                table_info.schema, table_info.name
+            );
+            return false;
+        }
+
+        copy
+    }
 }
 
-pub async fn init_client(postgres_url: &str) -> Result<Client> {
-    info!("Connecting to PostgreSQL at: {}", postgres_url);
-    let (client, connection) = tokio_postgres::connect(postgres_url, NoTls)
-        .await
-        .context("Failed to connect to PostgreSQL")?;
+// Tables are migrated once per layout, keyed by schema, name and column names
+fn migration_key(table_info: &TableInfo) -> (String, String, Vec<String>) {
+    (
+        table_info.schema.clone(),
+        table_info.name.clone(),
+        table_info.columns.iter().map(|c| c.name.clone()).collect(),
+    )
+}
+
+// Applies session settings to each new connection with set_config, which takes any value as is
+fn session_hook(settings: Vec<(String, String)>) -> Hook {
+    let settings = Arc::new(settings);
+    Hook::async_fn(move |client, _| {
+        let settings = settings.clone();
+        Box::pin(async move {
+            for (name, value) in settings.iter() {
+                client
+                    .execute("SELECT set_config($1, $2, false)", &[name, value])
+                    .await
+                    .map_err(|e| HookError::message(format!("Failed to set {name}: {e}")))?;
+            }
+            Ok(())
+        })
+    })
+}
+
+/// Parses a `name=value` session setting
+pub fn parse_session_setting(setting: &str) -> Result<(String, String)> {
+    match setting.split_once('=') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        }
+        _ => bail!("Invalid session setting '{setting}', expected name=value"),
+    }
+}
+
+/// Parses a pool recycling method: fast, verified or clean
+pub fn parse_recycling_method(method: &str) -> Result<RecyclingMethod> {
+    match method {
+        "fast" => Ok(RecyclingMethod::Fast),
+        "verified" => Ok(RecyclingMethod::Verified),
+        "clean" => Ok(RecyclingMethod::Clean),
+        _ => bail!("Invalid recycling method '{method}', expected fast, verified or clean"),
+    }
+}
+
+pub async fn init_client(postgres_url: &str, tls: &TlsOptions) -> Result<Client> {
+    let (config, tls) = pg_tls::configure(postgres_url, tls)?;
 
+    // The URL may hold a password, so only the hosts are logged
+    let hosts = config
+        .get_hosts()
+        .iter()
+        .map(|host| match host {
+            Host::Tcp(host) => host.clone(),
+            #[cfg(unix)]
+            Host::Unix(path) => path.display().to_string(),
+        })
+        .collect::<Vec<_>>();
+    info!("Connecting to PostgreSQL at: {}", hosts.join(", "));
+    let client = match tls {
+        Some(tls) => {
+            let (client, connection) = config
+                .connect(tls)
+                .await
+                .context("Failed to connect to PostgreSQL")?;
+            spawn_connection(connection);
+            client
+        }
+        None => {
+            let (client, connection) = config
+                .connect(NoTls)
+                .await
+                .context("Failed to connect to PostgreSQL")?;
+            spawn_connection(connection);
+            client
+        }
+    };
+
+    Ok(client)
+}
+
+fn spawn_connection(
+    connection: impl Future<Output = Result<(), tokio_postgres::Error>> + Send + 'static,
+) {
     tokio::spawn(async move {
         if let Err(e) = connection.await {
             eprintln!("connection error: {}", e);
         }
     });
-
-    Ok(client)
 }
 
+/// Values of one column across a batch, `None` being SQL NULL
 pub enum ColumnData {
-    Int(Vec<i32>),
-    Text(Vec<String>),
-    Bool(Vec<bool>),
-    Float(Vec<f64>),
-    // Add more as needed
+    SmallInt(Vec<Option<i16>>),
+    Int(Vec<Option<i32>>),
+    BigInt(Vec<Option<i64>>),
+    Numeric(Vec<Option<pg_value::Numeric>>),
+    Float(Vec<Option<f64>>),
+    Bool(Vec<Option<bool>>),
+    Text(Vec<Option<String>>),
+    Timestamp(Vec<Option<NaiveDateTime>>),
+    TimestampTz(Vec<Option<DateTime<Utc>>>),
+    Date(Vec<Option<NaiveDate>>),
+    Time(Vec<Option<NaiveTime>>),
+    Interval(Vec<Option<Interval>>),
+    Uuid(Vec<Option<Uuid>>),
+    Json(Vec<Option<Value>>),
+    Jsonb(Vec<Option<Value>>),
+    Bytea(Vec<Option<Vec<u8>>>),
+    Inet(Vec<Option<IpAddr>>),
+    /// Array literals, sent as text because UNNEST would flatten a two-dimensional parameter
+    Array(ScalarType, Vec<Option<String>>),
 }
 
 impl ColumnData {
+    /// Converts a column's values, failing on any value that doesn't match its declared type
+    pub fn from_values(col: &Column, values: &[Option<&Value>]) -> Result<Self> {
+        let column_type = ColumnType::parse(&col.r#type).ok_or_else(|| {
+            anyhow::anyhow!("Unsupported type '{}' for column {}", col.r#type, col.name)
+        })?;
+
+        let scalar = match column_type {
+            ColumnType::Scalar(scalar) => scalar,
+            ColumnType::Array(element) => {
+                let literals = convert(col, values, |value| {
+                    pg_value::to_array_literal(element, value)
+                })?;
+                return Ok(ColumnData::Array(element, literals));
+            }
+        };
+
+        let data = match scalar {
+            ScalarType::SmallInt => ColumnData::SmallInt(convert(col, values, pg_value::to_i16)?),
+            ScalarType::Int => ColumnData::Int(convert(col, values, pg_value::to_i32)?),
+            ScalarType::BigInt => ColumnData::BigInt(convert(col, values, pg_value::to_i64)?),
+            ScalarType::Numeric => ColumnData::Numeric(convert(col, values, pg_value::to_numeric)?),
+            ScalarType::Float => ColumnData::Float(convert(col, values, pg_value::to_f64)?),
+            ScalarType::Bool => ColumnData::Bool(convert(col, values, Value::as_bool)?),
+            ScalarType::Text => ColumnData::Text(convert(col, values, pg_value::to_string)?),
+            ScalarType::Timestamp => {
+                ColumnData::Timestamp(convert(col, values, pg_value::to_timestamp)?)
+            }
+            ScalarType::TimestampTz => {
+                ColumnData::TimestampTz(convert(col, values, pg_value::to_timestamptz)?)
+            }
+            ScalarType::Date => ColumnData::Date(convert(col, values, pg_value::to_date)?),
+            ScalarType::Time => ColumnData::Time(convert(col, values, pg_value::to_time)?),
+            ScalarType::Interval => {
+                ColumnData::Interval(convert(col, values, pg_value::to_interval)?)
+            }
+            ScalarType::Uuid => ColumnData::Uuid(convert(col, values, pg_value::to_uuid)?),
+            ScalarType::Json => {
+                ColumnData::Json(convert(col, values, |value| Some(value.clone()))?)
+            }
+            ScalarType::Jsonb => {
+                ColumnData::Jsonb(convert(col, values, |value| Some(value.clone()))?)
+            }
+            ScalarType::Bytea => ColumnData::Bytea(convert(col, values, pg_value::to_bytea)?),
+            ScalarType::Inet => ColumnData::Inet(convert(col, values, pg_value::to_inet)?),
+        };
+
+        Ok(data)
+    }
+
     pub fn as_sql_param(&self) -> &(dyn ToSql + Sync) {
         match self {
+            ColumnData::SmallInt(v) => v,
             ColumnData::Int(v) => v,
-            ColumnData::Text(v) => v,
-            ColumnData::Bool(v) => v,
+            ColumnData::BigInt(v) => v,
+            ColumnData::Numeric(v) => v,
             ColumnData::Float(v) => v,
+            ColumnData::Bool(v) => v,
+            ColumnData::Text(v) => v,
+            ColumnData::Timestamp(v) => v,
+            ColumnData::TimestampTz(v) => v,
+            ColumnData::Date(v) => v,
+            ColumnData::Time(v) => v,
+            ColumnData::Interval(v) => v,
+            ColumnData::Uuid(v) => v,
+            ColumnData::Json(v) => v,
+            ColumnData::Jsonb(v) => v,
+            ColumnData::Bytea(v) => v,
+            ColumnData::Inet(v) => v,
+            ColumnData::Array(_, v) => v,
         }
     }
 
+    /// Element type of the array parameter passed to UNNEST
     pub fn pg_type(&self) -> &'static str {
         match self {
-            ColumnData::Int(_) => "int",
-            ColumnData::Text(_) => "text",
-            ColumnData::Bool(_) => "bool",
-            ColumnData::Float(_) => "float8",
+            ColumnData::SmallInt(_) => ScalarType::SmallInt.pg_type(),
+            ColumnData::Int(_) => ScalarType::Int.pg_type(),
+            ColumnData::BigInt(_) => ScalarType::BigInt.pg_type(),
+            ColumnData::Numeric(_) => ScalarType::Numeric.pg_type(),
+            ColumnData::Float(_) => ScalarType::Float.pg_type(),
+            ColumnData::Bool(_) => ScalarType::Bool.pg_type(),
+            ColumnData::Text(_) | ColumnData::Array(..) => ScalarType::Text.pg_type(),
+            ColumnData::Timestamp(_) => ScalarType::Timestamp.pg_type(),
+            ColumnData::TimestampTz(_) => ScalarType::TimestampTz.pg_type(),
+            ColumnData::Date(_) => ScalarType::Date.pg_type(),
+            ColumnData::Time(_) => ScalarType::Time.pg_type(),
+            ColumnData::Interval(_) => ScalarType::Interval.pg_type(),
+            ColumnData::Uuid(_) => ScalarType::Uuid.pg_type(),
+            ColumnData::Json(_) => ScalarType::Json.pg_type(),
+            ColumnData::Jsonb(_) => ScalarType::Jsonb.pg_type(),
+            ColumnData::Bytea(_) => ScalarType::Bytea.pg_type(),
+            ColumnData::Inet(_) => ScalarType::Inet.pg_type(),
+        }
+    }
+
+    /// One row's value, as written by `COPY`
+    pub fn value(&self, row: usize) -> &(dyn ToSql + Sync) {
+        match self {
+            ColumnData::SmallInt(v) => &v[row],
+            ColumnData::Int(v) => &v[row],
+            ColumnData::BigInt(v) => &v[row],
+            ColumnData::Numeric(v) => &v[row],
+            ColumnData::Float(v) => &v[row],
+            ColumnData::Bool(v) => &v[row],
+            ColumnData::Text(v) => &v[row],
+            ColumnData::Timestamp(v) => &v[row],
+            ColumnData::TimestampTz(v) => &v[row],
+            ColumnData::Date(v) => &v[row],
+            ColumnData::Time(v) => &v[row],
+            ColumnData::Interval(v) => &v[row],
+            ColumnData::Uuid(v) => &v[row],
+            ColumnData::Json(v) => &v[row],
+            ColumnData::Jsonb(v) => &v[row],
+            ColumnData::Bytea(v) => &v[row],
+            ColumnData::Inet(v) => &v[row],
+            ColumnData::Array(_, v) => &v[row],
+        }
+    }
+
+    /// Type of the column in binary `COPY`, `None` for array columns which only support UNNEST
+    pub fn sql_type(&self) -> Option<Type> {
+        let scalar = match self {
+            ColumnData::SmallInt(_) => ScalarType::SmallInt,
+            ColumnData::Int(_) => ScalarType::Int,
+            ColumnData::BigInt(_) => ScalarType::BigInt,
+            ColumnData::Numeric(_) => ScalarType::Numeric,
+            ColumnData::Float(_) => ScalarType::Float,
+            ColumnData::Bool(_) => ScalarType::Bool,
+            ColumnData::Text(_) => ScalarType::Text,
+            ColumnData::Timestamp(_) => ScalarType::Timestamp,
+            ColumnData::TimestampTz(_) => ScalarType::TimestampTz,
+            ColumnData::Date(_) => ScalarType::Date,
+            ColumnData::Time(_) => ScalarType::Time,
+            ColumnData::Interval(_) => ScalarType::Interval,
+            ColumnData::Uuid(_) => ScalarType::Uuid,
+            ColumnData::Json(_) => ScalarType::Json,
+            ColumnData::Jsonb(_) => ScalarType::Jsonb,
+            ColumnData::Bytea(_) => ScalarType::Bytea,
+            ColumnData::Inet(_) => ScalarType::Inet,
+            ColumnData::Array(..) => return None,
+        };
+
+        Some(scalar.sql_type())
+    }
+
+    /// Cast applied to the unnested value before it is inserted, needed for array literals
+    pub fn select_cast(&self) -> Option<String> {
+        match self {
+            ColumnData::Array(element, _) => Some(format!("{}[]", element.pg_type())),
+            _ => None,
         }
     }
 }
 
-pub async fn insert_data(pool: &Pool, data: &TransformResult) -> Result<u64> {
+/// A row of a result, with the index of the input it came from when the plugin said so
+#[derive(Clone, Copy)]
+pub(crate) struct Row<'a> {
+    input: Option<usize>,
+    value: &'a Value,
+}
+
+/// A table's rows from one result, converted to the table's column types and ready to write
+pub(crate) struct TableWrite<'a> {
+    table_info: &'a TableInfo,
+    columns: Vec<Column>,
+    // Whether every column is sent in the table's own type rather than one Postgres casts
+    types_known: bool,
+    rows: Vec<Row<'a>>,
+    column_data: Vec<ColumnData>,
+}
+
+impl TableWrite<'_> {
+    // The same table with only some of its rows, converted again
+    fn part(&self, range: Range<usize>) -> Result<Self> {
+        let rows = self.rows[range].to_vec();
+        let column_data = build_column_data(&self.columns, &rows)?.unwrap_or_default();
+
+        Ok(Self {
+            table_info: self.table_info,
+            columns: self.columns.clone(),
+            types_known: self.types_known,
+            rows,
+            column_data,
+        })
+    }
+}
+
+/// Rows written for a result, and the rows Postgres rejected
+#[derive(Debug, Default)]
+pub struct Inserted {
+    pub rows: u64,
+    pub rejected: Vec<RejectedRow>,
+}
+
+/// A row Postgres refused, such as one violating a constraint, found by splitting its batch
+///
+/// Rows a lost connection left unwritten after others were written are reported this way too.
+#[derive(Debug)]
+pub struct RejectedRow {
+    /// Qualified name of the table the row was written to
+    pub table: String,
+    /// Index of the input the row came from, if the plugin reported it in an outcome or group
+    pub input: Option<usize>,
+    /// Position of the row among the result's rows for the table
+    pub position: usize,
+    pub row: Value,
+    /// SQLSTATE of the error, e.g. 23505 for a unique violation, or empty for a row left
+    /// unwritten by an error that wasn't about it
+    pub code: String,
+    pub message: String,
+    pub detail: Option<String>,
+}
+
+impl std::fmt::Display for RejectedRow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "row {} for {}", self.position, self.table)?;
+        if let Some(input) = self.input {
+            write!(f, " from input {input}")?;
+        }
+        write!(f, ": {}", self.message)?;
+        if !self.code.is_empty() {
+            write!(f, " ({})", self.code)?;
+        }
+        if let Some(detail) = &self.detail {
+            write!(f, ": {detail}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Inserts a result's rows, with every table in its own transaction unless grouped
+///
+/// When Postgres rejects a table's batch because of a row, such as a constraint violation or a
+/// bad value, the batch is split in halves until the rejected rows are isolated. The other rows
+/// are inserted and the rejected ones returned. Tables written in one transaction with
+/// `--group-transactions` fail as a whole instead.
+pub async fn insert_data(pool: &Pool, data: &TransformResult) -> Result<Inserted> {
+    let groups = result_groups(pool, data)?;
+
+    let mut connection = pool.connection().await?;
+    let tables = prepare_tables(pool, &mut connection, &groups).await?;
+
+    // Insert every table or none of them when the groups belong together
+    if pool.group_transactions && tables.len() > 1 {
+        let transaction = connection
+            .transaction()
+            .await
+            .context("Failed to start transaction")?;
+        let rows = write_tables(pool, &transaction, &tables).await?;
+        transaction
+            .commit()
+            .await
+            .context("Failed to commit transaction")?;
+        return Ok(Inserted {
+            rows,
+            ..Inserted::default()
+        });
+    }
+
+    let mut inserted = Inserted::default();
+    for table in &tables {
+        write_bisecting(pool, &mut connection, table, &mut inserted).await?;
+    }
+
+    Ok(inserted)
+}
+
+/// Splits a result into the rows of each of its tables
+pub(crate) fn result_groups<'a>(
+    pool: &Pool,
+    data: &'a TransformResult,
+) -> Result<Vec<(&'a TableInfo, Vec<Row<'a>>)>> {
     if !data.success {
         bail!("TransformResult indicates failure: {:?}", data.error);
     }
 
-    let table_info = data
-        .table_info
-        .as_ref()
-        .ok_or_else(|| anyhow::anyhow!("Missing table_info"))?;
+    let mut groups: Vec<(&TableInfo, Vec<Row>)> = Vec::with_capacity(data.tables.len() + 1);
 
-    let rows = data
-        .data
-        .as_ref()
-        .ok_or_else(|| anyhow::anyhow!("Missing data"))?;
+    match &data.table_info {
+        Some(table_info) => {
+            let rows = data
+                .indexed_rows()
+                .map(|(input, value)| Row { input, value })
+                .collect();
+            groups.push((table_info, rows));
+        }
+        None if data.tables.is_empty() => bail!("Missing table_info"),
+        // `data` and `ok` outcome rows only ever go to the top-level table
+        None if data.rows().next().is_some() => {
+            bail!("Result has data or ok outcome rows but no top-level table_info")
+        }
+        None => {}
+    }
 
-    if rows.is_empty() {
-        return Ok(0);
+    if data.table_info.is_some() && data.data.is_none() && data.outcomes.is_empty() {
+        bail!("Missing data");
     }
 
-    let columns = &table_info.columns;
+    for group in &data.tables {
+        if !group.inputs.is_empty() && group.inputs.len() != group.rows.len() {
+            bail!(
+                "Table group for {}.{} has {} rows but {} inputs",
+                group.table_info.schema,
+                group.table_info.name,
+                group.rows.len(),
+                group.inputs.len()
+            );
+        }
+        let rows = group
+            .indexed_rows()
+            .map(|(input, value)| Row { input, value })
+            .collect();
+        groups.push((&group.table_info, rows));
+    }
 
-    if columns.is_empty() {
-        warn!(
-            "TransformResult contained no columns, skipping {} rows",
-            rows.len()
-        );
-        return Ok(0);
+    for (table_info, _) in &groups {
+        pool.check_allowed(table_info)?;
     }
 
-    // Create a vector of ColumnData based on type
-    let mut column_data: Vec<ColumnData> = vec![];
+    // Tables without rows aren't migrated or checked, since nothing is written to them
+    groups.retain(|(_, rows)| !rows.is_empty());
 
-    for col in columns.iter() {
-        let mut col_values = Vec::with_capacity(rows.len());
+    Ok(groups)
+}
 
-        for row in rows {
-            let value = row
-                .get(&col.name)
-                .ok_or_else(|| anyhow::anyhow!("Missing column {} in row {:?}", &col.name, row))?;
+/// Migrates and checks each table, then converts its rows, without writing anything
+///
+/// Every table is converted before any is written, so a bad value can't leave a result half
+/// written.
+pub(crate) async fn prepare_tables<'a>(
+    pool: &Pool,
+    connection: &mut deadpool_postgres::Client,
+    groups: &[(&'a TableInfo, Vec<Row<'a>>)],
+) -> Result<Vec<TableWrite<'a>>> {
+    let mut tables = Vec::with_capacity(groups.len());
 
-            // Add value to the appropriate vector
-            match (col.r#type.as_str(), value) {
-                ("int" | "integer", Value::Number(n)) if n.is_i64() => {
-                    col_values.push(ColumnData::Int(vec![n.as_i64().unwrap() as i32]));
-                }
-                ("string" | "text" | "varchar", Value::String(s)) => {
-                    col_values.push(ColumnData::Text(vec![s.clone()]));
-                }
-                ("string" | "text" | "varchar", Value::Number(s)) => {
-                    col_values.push(ColumnData::Text(vec![s.to_string()]));
+    for (table_info, rows) in groups {
+        pool.migrate(connection, table_info).await?;
+        let (columns, types_known) = pool.resolve_columns(&*connection, table_info).await?;
+
+        if let Some(column_data) = build_column_data(&columns, rows)? {
+            tables.push(TableWrite {
+                table_info,
+                columns,
+                types_known,
+                rows: rows.clone(),
+                column_data,
+            });
+        }
+    }
+
+    Ok(tables)
+}
+
+/// Writes prepared tables with a client that is already in a transaction
+pub(crate) async fn write_tables(
+    pool: &Pool,
+    client: &impl PooledClient,
+    tables: &[TableWrite<'_>],
+) -> Result<u64> {
+    let mut inserted = 0;
+    for table in tables {
+        inserted += if pool.use_copy(table) {
+            copy_rows(client, table).await?
+        } else {
+            insert_rows(pool, client, table).await?
+        };
+    }
+
+    Ok(inserted)
+}
+
+/// Writes ranges of a batch's rows, so [`bisect`] can split them without knowing where they go
+pub trait RangeWriter {
+    /// Writes the rows in `rows`, returning how many were inserted
+    fn write(&mut self, rows: Range<usize>) -> impl Future<Output = Result<u64>>;
+
+    /// Returns the SQLSTATE of an error caused by the rows written, rather than the statement or
+    /// connection
+    fn row_error_code(&self, error: &anyhow::Error) -> Option<String>;
+}
+
+/// Outcome of writing a batch with [`bisect`]
+#[derive(Debug, Default)]
+pub struct Bisected {
+    pub rows: u64,
+    /// Position of each rejected row along with the error it was rejected with
+    pub rejected: Vec<(usize, anyhow::Error)>,
+    /// Rows left unwritten by an error that wasn't about them, such as a lost connection, once
+    /// other rows were already written
+    pub unwritten: Option<(Vec<Range<usize>>, anyhow::Error)>,
+}
+
+/// Writes `len` rows, splitting them in halves while they're rejected to find the bad ones
+///
+/// Parts are written one after another, so the rows before a bad one are already written when
+/// it is found. The batch only fails as a whole while nothing is written: once rows are, an
+/// error that isn't about particular rows leaves the rest in [`Bisected::unwritten`].
+pub async fn bisect(writer: &mut impl RangeWriter, len: usize) -> Result<Bisected> {
+    let mut bisected = Bisected::default();
+    let error = match writer.write(0..len).await {
+        Ok(rows) => {
+            bisected.rows = rows;
+            return Ok(bisected);
+        }
+        Err(e) => e,
+    };
+    let Some(code) = writer.row_error_code(&error) else {
+        return Err(error);
+    };
+    if len == 1 {
+        bisected.rejected.push((0, error));
+        return Ok(bisected);
+    }
+
+    // An error that hits every row, such as a NOT NULL column left out, fails the first and the
+    // last row alike, so finding out costs two statements rather than splitting down to every row
+    let mut failed = Vec::new();
+    let mut last = None;
+    match writer.write(0..1).await {
+        Ok(rows) => {
+            // The batch failed without the first row, so the rest holds a bad one
+            bisected.rows += rows;
+            failed.push((1..len, error));
+        }
+        Err(first) => {
+            let Some(first_code) = writer.row_error_code(&first) else {
+                return Err(first);
+            };
+            let mut end = len;
+            if first_code == code {
+                match writer.write(len - 1..len).await {
+                    Ok(rows) => bisected.rows += rows,
+                    Err(e) => match writer.row_error_code(&e) {
+                        Some(last_code) if last_code == code => return Err(error),
+                        Some(_) => last = Some((len - 1, e)),
+                        None => return Err(e),
+                    },
                 }
-                ("bool", Value::Bool(b)) => {
-                    col_values.push(ColumnData::Bool(vec![*b]));
+                end = len - 1;
+            }
+            bisected.rejected.push((0, first));
+
+            // The first row alone may have failed the batch, so the rows after it are written
+            // before they're split
+            if end > 1 {
+                match writer.write(1..end).await {
+                    Ok(rows) => bisected.rows += rows,
+                    Err(e) if writer.row_error_code(&e).is_some() => failed.push((1..end, e)),
+                    Err(e) if bisected.rows == 0 => return Err(e),
+                    Err(e) => bisected.unwritten = Some((std::iter::once(1..end).collect(), e)),
                 }
-                ("float" | "float8" | "double", Value::Number(n)) if n.is_f64() || n.is_i64() => {
-                    col_values.push(ColumnData::Float(vec![n.as_f64().unwrap()]));
+            }
+        }
+    }
+
+    'split: while let Some((range, error)) = failed.pop() {
+        if range.len() == 1 {
+            bisected.rejected.push((range.start, error));
+            continue;
+        }
+
+        let middle = range.start + range.len() / 2;
+        let mut halves = Vec::with_capacity(2);
+        let mut split = [range.start..middle, middle..range.end].into_iter();
+        while let Some(half) = split.next() {
+            match writer.write(half.clone()).await {
+                Ok(rows) => bisected.rows += rows,
+                Err(e) if writer.row_error_code(&e).is_some() => halves.push((half, e)),
+                Err(e) if bisected.rows == 0 => return Err(e),
+                Err(e) => {
+                    // Written rows can't be taken back, so report the rest instead of failing
+                    let rows = halves
+                        .into_iter()
+                        .map(|(half, _)| half)
+                        .chain([half])
+                        .chain(split)
+                        .chain(failed.into_iter().rev().map(|(range, _)| range))
+                        .collect();
+                    bisected.unwritten = Some((rows, e));
+                    break 'split;
                 }
-                t => bail!(
-                    "Type mismatch or unsupported '{t:?}' for column {} in {row:?}",
-                    col.name
-                ),
             }
         }
 
-        // Flatten column values
-        let merged = match col.r#type.as_str() {
-            "int" | "integer" => ColumnData::Int(
-                col_values
-                    .into_iter()
-                    .flat_map(|c| match c {
-                        ColumnData::Int(v) => v,
-                        _ => unreachable!(),
-                    })
-                    .collect(),
-            ),
-            "text" | "varchar" | "string" => ColumnData::Text(
-                col_values
-                    .into_iter()
-                    .flat_map(|c| match c {
-                        ColumnData::Text(v) => v,
-                        _ => unreachable!(),
-                    })
-                    .collect(),
-            ),
-            "bool" => ColumnData::Bool(
-                col_values
-                    .into_iter()
-                    .flat_map(|c| match c {
-                        ColumnData::Bool(v) => v,
-                        _ => unreachable!(),
-                    })
-                    .collect(),
-            ),
-            "float" | "float8" | "double" => ColumnData::Float(
-                col_values
-                    .into_iter()
-                    .flat_map(|c| match c {
-                        ColumnData::Float(v) => v,
-                        _ => unreachable!(),
-                    })
-                    .collect(),
-            ),
-            t => bail!("Unsupported type merge: {t}"),
-        };
+        // Halves are pushed in reverse, so rows are written in their original order
+        failed.extend(halves.into_iter().rev());
+    }
+
+    bisected.rejected.extend(last);
+    Ok(bisected)
+}
+
+// Writes parts of a table outside of a transaction
+struct TableWriter<'a, 'b> {
+    pool: &'a Pool,
+    connection: &'a mut deadpool_postgres::Client,
+    table: &'a TableWrite<'b>,
+}
 
-        column_data.push(merged);
+impl RangeWriter for TableWriter<'_, '_> {
+    async fn write(&mut self, rows: Range<usize>) -> Result<u64> {
+        if rows == (0..self.table.rows.len()) {
+            return write_table(self.pool, self.connection, self.table).await;
+        }
+        write_table(self.pool, self.connection, &self.table.part(rows)?).await
     }
 
-    // Construct SQL
-    let column_names = columns
+    fn row_error_code(&self, error: &anyhow::Error) -> Option<String> {
+        row_error(error).map(|db_error| db_error.code().code().to_string())
+    }
+}
+
+/// Writes a table, splitting its rows in halves while Postgres rejects them to find the bad ones
+async fn write_bisecting(
+    pool: &Pool,
+    connection: &mut deadpool_postgres::Client,
+    table: &TableWrite<'_>,
+    inserted: &mut Inserted,
+) -> Result<()> {
+    let mut writer = TableWriter {
+        pool,
+        connection,
+        table,
+    };
+    let bisected = bisect(&mut writer, table.rows.len()).await?;
+
+    inserted.rows += bisected.rows;
+    let unwritten = bisected.unwritten.iter().flat_map(|(ranges, error)| {
+        ranges
+            .iter()
+            .flat_map(|range| range.clone())
+            .map(move |position| (position, error))
+    });
+    let rejected = bisected
+        .rejected
         .iter()
-        .map(|c| c.name.as_str())
-        .collect::<Vec<_>>()
-        .join(", ");
+        .map(|(position, error)| (*position, error));
+    for (position, error) in rejected.chain(unwritten) {
+        inserted
+            .rejected
+            .push(rejected_row(table, position, error)?);
+    }
+
+    Ok(())
+}
+
+// Describes a row that wasn't written, with the Postgres error if it was refused
+fn rejected_row(
+    table: &TableWrite<'_>,
+    position: usize,
+    error: &anyhow::Error,
+) -> Result<RejectedRow> {
+    let row = table.rows[position];
+    let (code, message, detail) = match row_error(error) {
+        Some(db_error) => (
+            db_error.code().code().to_string(),
+            db_error.message().to_string(),
+            db_error.detail().map(str::to_string),
+        ),
+        None => (String::new(), format!("{error:#}"), None),
+    };
+
+    Ok(RejectedRow {
+        table: qualified_table(table.table_info)?,
+        input: row.input,
+        position,
+        row: row.value.clone(),
+        code,
+        message,
+        detail,
+    })
+}
+
+/// Writes one table outside of a transaction
+async fn write_table(
+    pool: &Pool,
+    connection: &mut deadpool_postgres::Client,
+    table: &TableWrite<'_>,
+) -> Result<u64> {
+    if !pool.use_copy(table) {
+        return insert_rows(pool, &*connection, table).await;
+    }
+
+    // COPY gets its own transaction so an upsert's staging table is dropped on commit
+    let transaction = connection
+        .transaction()
+        .await
+        .context("Failed to start transaction")?;
+    let copied = copy_rows(&transaction, table).await?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit transaction")?;
+    Ok(copied)
+}
+
+// Errors caused by the rows written rather than the statement or connection: data exceptions
+// (class 22) and integrity constraint violations (class 23)
+fn row_error(error: &anyhow::Error) -> Option<&DbError> {
+    let db_error = error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<tokio_postgres::Error>())?
+        .as_db_error()?;
+
+    let class = &db_error.code().code()[..2];
+    matches!(class, "22" | "23").then_some(db_error)
+}
+
+/// Converts a group's rows into one `ColumnData` per column, or `None` if there's nothing to insert
+fn build_column_data(columns: &[Column], rows: &[Row]) -> Result<Option<Vec<ColumnData>>> {
+    if rows.is_empty() {
+        return Ok(None);
+    }
+
+    if columns.is_empty() {
+        warn!(
+            "TransformResult contained no columns, skipping {} rows",
+            rows.len()
+        );
+        return Ok(None);
+    }
+
+    // Create a vector of ColumnData based on type
+    let mut column_data: Vec<ColumnData> = Vec::with_capacity(columns.len());
+
+    for col in columns.iter() {
+        let values = rows
+            .iter()
+            .map(|row| column_value(col, row.value))
+            .collect::<Result<Vec<_>>>()?;
+
+        column_data.push(ColumnData::from_values(col, &values)?);
+    }
+
+    Ok(Some(column_data))
+}
+
+/// Inserts a group with a single `INSERT ... SELECT * FROM UNNEST(...)` statement
+async fn insert_rows(
+    pool: &Pool,
+    client: &impl PooledClient,
+    table: &TableWrite<'_>,
+) -> Result<u64> {
+    let TableWrite {
+        table_info,
+        column_data,
+        ..
+    } = table;
+
+    // Construct SQL
+    let table = qualified_table(table_info)?;
+    let column_names = quote_idents(table_info.columns.iter().map(|c| c.name.as_str()))?;
 
     let unnest_args = column_data
         .iter()
@@ -197,33 +1105,32 @@ pub async fn insert_data(pool: &Pool, data: &TransformResult) -> Result<u64> {
         .collect::<Vec<_>>()
         .join(", ");
 
-    let connection = pool.db.get().await;
-    if connection.is_err() {
-        println!("Connection: {connection:?}");
-    }
-    let connection = connection?;
-
-    let statement = match pool.query_cache.entry((
-        table_info.schema.clone(),
-        table_info.name.clone(),
-        column_names.clone(),
-        unnest_args.clone(),
-    )) {
-        Entry::Occupied(occupied_entry) => occupied_entry.get().clone(),
-        Entry::Vacant(vacant_entry) => {
-            let query = format!(
-                "INSERT INTO {}.{} ({}) SELECT * FROM UNNEST({})",
-                table_info.schema, table_info.name, column_names, unnest_args
-            );
-            let statement = connection.prepare(query.as_str()).await?;
-            vacant_entry.insert_entry(statement.clone());
-            statement
-        }
+    // Array columns arrive as text literals and are cast back to arrays when selected
+    let source = if column_data.iter().any(|c| c.select_cast().is_some()) {
+        let select = column_data
+            .iter()
+            .enumerate()
+            .map(|(i, c)| match c.select_cast() {
+                Some(cast) => format!("c{}::{cast}", i + 1),
+                None => format!("c{}", i + 1),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let aliases = (1..=column_data.len())
+            .map(|i| format!("c{i}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!("SELECT {select} FROM UNNEST({unnest_args}) AS u({aliases})")
+    } else {
+        format!("SELECT * FROM UNNEST({unnest_args})")
     };
 
+    let on_conflict = on_conflict_clause(table_info)?;
+
+    let query = format!("INSERT INTO {table} ({column_names}) {source}{on_conflict}");
     let params: Vec<&(dyn ToSql + Sync)> = column_data.iter().map(|c| c.as_sql_param()).collect();
 
-    let inserted = connection.execute(&statement, &params).await;
+    let inserted = pool.statements.execute(client, &query, &params).await;
     if inserted.is_err() {
         println!("InsertedRes: {inserted:?}");
     }
@@ -232,3 +1139,231 @@ pub async fn insert_data(pool: &Pool, data: &TransformResult) -> Result<u64> {
 
     Ok(inserted)
 }
+
+// Process-wide counter so staging tables created in one transaction don't collide
+static NEXT_STAGING_TABLE: AtomicU64 = AtomicU64::new(0);
+
+/// Loads a group with binary `COPY`, merging through a temporary staging table for upserts
+async fn copy_rows(client: &impl PooledClient, table: &TableWrite<'_>) -> Result<u64> {
+    let TableWrite {
+        table_info,
+        rows,
+        column_data,
+        ..
+    } = table;
+    let client = client.client();
+    let column_names = quote_idents(table_info.columns.iter().map(|c| c.name.as_str()))?;
+    let table = qualified_table(table_info)?;
+    let on_conflict = on_conflict_clause(table_info)?;
+
+    // COPY has no ON CONFLICT, so upserts are copied aside and merged with INSERT ... SELECT
+    let target = if on_conflict.is_empty() {
+        table.clone()
+    } else {
+        let staging = format!(
+            "staging_{}",
+            NEXT_STAGING_TABLE.fetch_add(1, Ordering::Relaxed)
+        );
+        // Only the written columns are staged, as LIKE would copy the NOT NULL of a column that
+        // is left to its identity or default without the identity itself
+        client
+            .batch_execute(&format!(
+                "CREATE TEMP TABLE {staging} ON COMMIT DROP AS \
+                 SELECT {column_names} FROM {table} WITH NO DATA"
+            ))
+            .await
+            .with_context(|| format!("Failed to create staging table for {table}"))?;
+        staging
+    };
+
+    let types = column_data
+        .iter()
+        .map(ColumnData::sql_type)
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| anyhow::anyhow!("Array columns can't be loaded with COPY"))?;
+
+    let copy = format!("COPY {target} ({column_names}) FROM STDIN (FORMAT binary)");
+    let sink = client
+        .copy_in(copy.as_str())
+        .await
+        .with_context(|| format!("Failed to start COPY into {table}"))?;
+    let writer = BinaryCopyInWriter::new(sink, &types);
+    pin!(writer);
+
+    for row in 0..rows.len() {
+        let values: Vec<&(dyn ToSql + Sync)> = column_data.iter().map(|c| c.value(row)).collect();
+        writer.as_mut().write(&values).await?;
+    }
+
+    let copied = writer
+        .finish()
+        .await
+        .with_context(|| format!("Failed to COPY rows into {table}"))?;
+
+    if on_conflict.is_empty() {
+        return Ok(copied);
+    }
+
+    let merge = format!(
+        "INSERT INTO {table} ({column_names}) SELECT {column_names} FROM {target}{on_conflict}"
+    );
+    let merged = client
+        .execute(merge.as_str(), &[])
+        .await
+        .with_context(|| format!("Failed to merge staged rows into {table}"))?;
+
+    Ok(merged)
+}
+
+/// Longest identifier Postgres stores without truncating it (NAMEDATALEN - 1)
+const MAX_IDENTIFIER_LEN: usize = 63;
+
+/// Quotes an identifier for SQL, so any name is taken literally and can't inject SQL
+pub fn quote_ident(ident: &str) -> Result<String> {
+    if ident.is_empty() {
+        bail!("SQL identifier is empty");
+    }
+    if ident.contains('\0') {
+        bail!("SQL identifier {ident:?} contains a NUL character");
+    }
+    if ident.len() > MAX_IDENTIFIER_LEN {
+        bail!("SQL identifier {ident:?} is longer than {MAX_IDENTIFIER_LEN} bytes");
+    }
+
+    Ok(format!("\"{}\"", ident.replace('"', "\"\"")))
+}
+
+pub(crate) fn quote_idents<'a>(idents: impl IntoIterator<Item = &'a str>) -> Result<String> {
+    Ok(idents
+        .into_iter()
+        .map(quote_ident)
+        .collect::<Result<Vec<_>>>()?
+        .join(", "))
+}
+
+pub(crate) fn qualified_table(table_info: &TableInfo) -> Result<String> {
+    Ok(format!(
+        "{}.{}",
+        quote_ident(&table_info.schema)?,
+        quote_ident(&table_info.name)?
+    ))
+}
+
+/// Builds the `ON CONFLICT` clause for a table, or an empty string for plain inserts
+pub fn on_conflict_clause(table_info: &TableInfo) -> Result<String> {
+    let target = table_info.conflict_target();
+    let policy = match (&table_info.on_conflict, target.is_empty()) {
+        (Some(policy), _) => policy.clone(),
+        (None, false) => ConflictPolicy::UpdateAll,
+        (None, true) => return Ok(String::new()),
+    };
+
+    if target.is_empty() && policy != ConflictPolicy::DoNothing {
+        bail!(
+            "Table {}.{} needs a primary_key or conflict_columns to update on conflict",
+            table_info.schema,
+            table_info.name
+        );
+    }
+
+    let conflict = if target.is_empty() {
+        " ON CONFLICT".to_string()
+    } else {
+        format!(
+            " ON CONFLICT ({})",
+            quote_idents(target.iter().map(String::as_str))?
+        )
+    };
+
+    // Columns outside the conflict target, which are the ones an update can change
+    let non_key_columns = || {
+        table_info
+            .columns
+            .iter()
+            .map(|c| c.name.clone())
+            .filter(|name| !target.contains(name))
+            .collect::<Vec<_>>()
+    };
+
+    // EXCLUDED holds a column's default for one the batch doesn't insert, which would overwrite
+    // existing data, or never compare as newer
+    let check_declared = |column: &String| {
+        if !table_info.columns.iter().any(|c| &c.name == column) {
+            bail!(
+                "On conflict column {column} of {}.{} isn't one of the table_info columns",
+                table_info.schema,
+                table_info.name
+            );
+        }
+        Ok(())
+    };
+
+    let (columns, condition) = match policy {
+        ConflictPolicy::DoNothing => return Ok(format!("{conflict} DO NOTHING")),
+        ConflictPolicy::UpdateAll => (non_key_columns(), String::new()),
+        ConflictPolicy::Update { columns } => {
+            columns.iter().try_for_each(check_declared)?;
+            (columns, String::new())
+        }
+        ConflictPolicy::UpdateIfNewer { version_column } => {
+            check_declared(&version_column)?;
+            let table = qualified_table(table_info)?;
+            let version = quote_ident(&version_column)?;
+            (
+                non_key_columns(),
+                format!(" WHERE {table}.{version} < EXCLUDED.{version}"),
+            )
+        }
+    };
+
+    // Nothing left to overwrite, so an update would be a no-op
+    if columns.is_empty() {
+        return Ok(format!("{conflict} DO NOTHING"));
+    }
+
+    let assignments = columns
+        .iter()
+        .map(|column| {
+            let column = quote_ident(column)?;
+            Ok(format!("{column} = EXCLUDED.{column}"))
+        })
+        .collect::<Result<Vec<_>>>()?
+        .join(", ");
+
+    Ok(format!("{conflict} DO UPDATE SET {assignments}{condition}"))
+}
+
+/// Resolves a row's value for a column, using the column's default when the key is missing
+///
+/// Returns `None` for SQL NULL, which is only allowed for nullable columns.
+fn column_value<'a>(col: &'a Column, row: &'a Value) -> Result<Option<&'a Value>> {
+    match row.get(&col.name).or(col.default.as_ref()) {
+        Some(Value::Null) | None if col.nullable => Ok(None),
+        Some(Value::Null) | None => bail!(
+            "Missing value for non-nullable column {} in row {row:?}",
+            col.name
+        ),
+        Some(value) => Ok(Some(value)),
+    }
+}
+
+// Converts every non-NULL value of a column, naming the column and value on a mismatch
+fn convert<T>(
+    col: &Column,
+    values: &[Option<&Value>],
+    convert_value: impl Fn(&Value) -> Option<T>,
+) -> Result<Vec<Option<T>>> {
+    values
+        .iter()
+        .map(|value| match value {
+            Some(value) => convert_value(value).map(Some).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Type mismatch for column {} of type '{}': {value}",
+                    col.name,
+                    col.r#type
+                )
+            }),
+            None => Ok(None),
+        })
+        .collect()
+}
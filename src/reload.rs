@@ -0,0 +1,190 @@
+use anyhow::{Context, Result};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{Mutex, mpsc};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::deno::{DenoPool, Flushed, PluginOptions, PoolMetrics, TransformResult};
+
+// Editors often write a file in several steps, wait for them to settle before reloading
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(250);
+
+// How often a replaced pool checks whether its last in-flight batch has finished
+const RETIRED_POOL_POLL: Duration = Duration::from_millis(50);
+
+/// A `DenoPool` that can be replaced with one built from updated plugin code
+///
+/// Each batch runs on the pool that was current when it started, so swapping pools never drops
+/// in-flight batches. The old pool shuts down once its last batch finishes.
+pub struct ReloadablePool {
+    plugin_path: PathBuf,
+    options: PluginOptions,
+    current: RwLock<Arc<DenoPool>>,
+    retired: std::sync::Mutex<Vec<TransformResult>>,
+    reloading: Mutex<()>,
+}
+
+impl ReloadablePool {
+    pub fn new(plugin_path: &Path, options: PluginOptions) -> Result<Self> {
+        let pool = DenoPool::with_options(plugin_path, options.clone())?;
+
+        Ok(Self {
+            plugin_path: plugin_path.to_path_buf(),
+            options,
+            current: RwLock::new(Arc::new(pool)),
+            retired: std::sync::Mutex::new(Vec::new()),
+            reloading: Mutex::new(()),
+        })
+    }
+
+    fn current(&self) -> Arc<DenoPool> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub async fn execute(&self, values: Vec<Value>) -> Result<TransformResult> {
+        self.current().execute(values).await
+    }
+
+    pub fn metrics(&self) -> PoolMetrics {
+        self.current().metrics()
+    }
+
+    pub fn options(&self) -> &PluginOptions {
+        &self.options
+    }
+
+    /// Flushes the current plugin, along with anything a replaced plugin had buffered
+    pub async fn flush(&self) -> Flushed {
+        let mut flushed = self.current().flush().await;
+
+        // Rows the replaced plugin handed over are returned even if the current one failed
+        let retired = std::mem::take(&mut *self.retired.lock().unwrap());
+        flushed.results.splice(0..0, retired);
+        flushed
+    }
+
+    /// Builds a pool from the plugin file on disk and swaps it in once it passes a smoke transform
+    ///
+    /// If the new plugin fails to load or transform, the current pool keeps running.
+    pub async fn reload(&self) -> Result<()> {
+        let _reloading = self.reloading.lock().await;
+        info!("Reloading plugin from {:?}", self.plugin_path);
+
+        let plugin_path = self.plugin_path.clone();
+        let options = self.options.clone();
+        let pool =
+            tokio::task::spawn_blocking(move || DenoPool::with_options(&plugin_path, options))
+                .await
+                .context("Failed to join plugin reload task")?
+                .context("Failed to load updated plugin")?;
+
+        // Make sure the new code can actually run a batch before sending it real messages. Only
+        // a thrown error or something that isn't a result fails it, since a plugin may well
+        // report `success: false` for a batch with nothing in it
+        if let Err(e) = pool.execute(Vec::new()).await {
+            shutdown_in_background(Arc::new(pool));
+            return Err(e.context("Updated plugin failed its smoke transform"));
+        }
+
+        let previous = std::mem::replace(&mut *self.current.write().unwrap(), Arc::new(pool));
+
+        // Hold on to rows the old plugin had buffered so the next flush still returns them
+        let flushed = previous.flush().await;
+        self.retired.lock().unwrap().extend(flushed.results);
+        for e in flushed.errors {
+            warn!("Failed to flush replaced plugin: {e:#}");
+        }
+        shutdown_in_background(previous);
+
+        info!("Reloaded plugin from {:?}", self.plugin_path);
+        Ok(())
+    }
+}
+
+// Dropping a pool joins its worker threads, keep that off the async executor. Batches still in
+// flight hold clones of the pool, so wait for them to finish rather than letting the last one
+// drop it on an executor thread
+fn shutdown_in_background(mut pool: Arc<DenoPool>) {
+    tokio::task::spawn_blocking(move || {
+        loop {
+            match Arc::try_unwrap(pool) {
+                Ok(pool) => {
+                    drop(pool);
+                    return;
+                }
+                Err(shared) => pool = shared,
+            }
+            std::thread::sleep(RETIRED_POOL_POLL);
+        }
+    });
+}
+
+/// Reloads the plugin on SIGHUP, and whenever the plugin file changes if `watch_file` is set
+pub fn spawn_reloader(pool: Arc<ReloadablePool>, watch_file: bool) -> Result<JoinHandle<()>> {
+    let (changed, mut changes) = mpsc::channel(1);
+
+    let watcher = if watch_file {
+        Some(watch_plugin_file(&pool.plugin_path, changed)?)
+    } else {
+        None
+    };
+
+    let mut hangup = signal(SignalKind::hangup()).context("Failed to listen for SIGHUP")?;
+
+    Ok(tokio::spawn(async move {
+        // Keep the watcher alive for as long as we're reloading
+        let _watcher = watcher;
+
+        loop {
+            tokio::select! {
+                Some(()) = changes.recv() => {
+                    tokio::time::sleep(RELOAD_DEBOUNCE).await;
+                    while changes.try_recv().is_ok() {}
+                    info!("Plugin file changed");
+                }
+                Some(()) = hangup.recv() => info!("Received SIGHUP"),
+                else => break,
+            }
+
+            if let Err(e) = pool.reload().await {
+                warn!("Plugin reload failed, keeping the current plugin: {e:#}");
+            }
+        }
+    }))
+}
+
+fn watch_plugin_file(plugin_path: &Path, changed: mpsc::Sender<()>) -> Result<RecommendedWatcher> {
+    let plugin_path = plugin_path
+        .canonicalize()
+        .context("Failed to resolve plugin path")?;
+
+    // Watch the parent directory so editors that replace the file with a rename are noticed
+    let directory = plugin_path
+        .parent()
+        .context("Plugin path has no parent directory")?
+        .to_path_buf();
+
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event)
+                if (event.kind.is_create() || event.kind.is_modify())
+                    && event.paths.iter().any(|path| path == &plugin_path) =>
+            {
+                let _ = changed.try_send(());
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Plugin file watch error: {e}"),
+        })
+        .context("Failed to create plugin file watcher")?;
+
+    watcher
+        .watch(&directory, RecursiveMode::NonRecursive)
+        .context("Failed to watch plugin file")?;
+
+    Ok(watcher)
+}
@@ -0,0 +1,102 @@
+//! Checks the columns a plugin declares against the live definition of its target table
+use anyhow::{Context, Result, bail};
+use deadpool_postgres::GenericClient;
+use tracing::debug;
+
+use crate::deno::{Column, TableInfo};
+use crate::pg_value::ColumnType;
+
+/// A column as Postgres defines it
+#[derive(Clone, Debug)]
+pub struct DbColumn {
+    pub name: String,
+    /// `None` for types values can't be coerced to, which are left for Postgres to cast
+    pub column_type: Option<ColumnType>,
+    pub nullable: bool,
+}
+
+/// Reads a table's columns from `information_schema`, failing if the table doesn't exist
+pub async fn fetch_columns(
+    client: &impl GenericClient,
+    schema: &str,
+    name: &str,
+) -> Result<Vec<DbColumn>> {
+    let rows = client
+        .query(
+            "SELECT column_name::text, udt_name::text, is_nullable = 'YES' \
+             FROM information_schema.columns \
+             WHERE table_schema = $1 AND table_name = $2",
+            &[&schema, &name],
+        )
+        .await
+        .with_context(|| format!("Failed to read columns of {schema}.{name}"))?;
+
+    if rows.is_empty() {
+        bail!("Table {schema}.{name} does not exist");
+    }
+
+    Ok(rows
+        .iter()
+        .map(|row| DbColumn {
+            name: row.get(0),
+            column_type: ColumnType::from_udt_name(row.get(1)),
+            nullable: row.get(2),
+        })
+        .collect())
+}
+
+/// Matches declared columns to the table's, taking the table's type and nullability
+///
+/// Values are then converted to the real column type rather than the declared one, and a
+/// NOT NULL column rejects missing values before anything is sent to Postgres.
+pub fn resolve_columns(table_info: &TableInfo, db_columns: &[DbColumn]) -> Result<Vec<Column>> {
+    table_info
+        .columns
+        .iter()
+        .map(|col| {
+            let Some(db_column) = db_columns.iter().find(|db| db.name == col.name) else {
+                bail!(
+                    "Column {} does not exist in {}.{}",
+                    col.name,
+                    table_info.schema,
+                    table_info.name
+                );
+            };
+
+            let declared = ColumnType::parse(&col.r#type).ok_or_else(|| {
+                anyhow::anyhow!("Unsupported type '{}' for column {}", col.r#type, col.name)
+            })?;
+
+            let r#type = match db_column.column_type {
+                Some(actual) if actual != declared => {
+                    if matches!(actual, ColumnType::Array(_))
+                        != matches!(declared, ColumnType::Array(_))
+                    {
+                        bail!(
+                            "Column {} of {}.{} is declared as {} but is {} in the table",
+                            col.name,
+                            table_info.schema,
+                            table_info.name,
+                            col.r#type,
+                            actual
+                        );
+                    }
+
+                    debug!(
+                        "Coercing column {} of {}.{} from {} to {actual}",
+                        col.name, table_info.schema, table_info.name, col.r#type
+                    );
+                    actual.to_string()
+                }
+                _ => col.r#type.clone(),
+            };
+
+            Ok(Column {
+                name: col.name.clone(),
+                r#type,
+                nullable: col.nullable && db_column.nullable,
+                default: col.default.clone(),
+            })
+        })
+        .collect()
+}
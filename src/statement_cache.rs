@@ -0,0 +1,207 @@
+//! Prepared statements cached per pooled connection, since a statement only exists on the
+//! connection that prepared it
+use lru::LruCache;
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex, Weak};
+use tokio_postgres::error::SqlState;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{Client, Error, Statement};
+use tracing::debug;
+
+/// A pooled connection, or a transaction on one
+pub trait PooledClient: Sync {
+    /// Client of the connection, which runs statements in its transaction if there is one
+    fn client(&self) -> &Client;
+
+    /// Deadpool's statement cache of the connection, which is only used to identify it
+    fn connection(&self) -> &Arc<deadpool_postgres::StatementCache>;
+
+    /// Whether a statement can be run again after failing, which an aborted transaction can't
+    fn can_retry(&self) -> bool;
+}
+
+impl PooledClient for deadpool_postgres::Client {
+    fn client(&self) -> &Client {
+        self
+    }
+
+    fn connection(&self) -> &Arc<deadpool_postgres::StatementCache> {
+        &self.statement_cache
+    }
+
+    fn can_retry(&self) -> bool {
+        true
+    }
+}
+
+impl PooledClient for deadpool_postgres::Transaction<'_> {
+    fn client(&self) -> &Client {
+        tokio_postgres::Transaction::client(self)
+    }
+
+    fn connection(&self) -> &Arc<deadpool_postgres::StatementCache> {
+        &self.statement_cache
+    }
+
+    fn can_retry(&self) -> bool {
+        false
+    }
+}
+
+// Entries of one connection
+struct ConnectionEntries<C, V> {
+    // Keeps the connection's allocation, and so its address, from being reused while cached
+    connection: Weak<C>,
+    entries: LruCache<String, V>,
+}
+
+/// Least recently used values, up to `capacity` per connection, for connections identified by
+/// their `Arc`
+///
+/// Connections the pool has closed are forgotten the next time a new one is tracked.
+pub struct ConnectionLru<C, V> {
+    capacity: NonZeroUsize,
+    connections: HashMap<usize, ConnectionEntries<C, V>>,
+}
+
+impl<C, V: Clone> ConnectionLru<C, V> {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            capacity,
+            connections: HashMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, connection: &Arc<C>, key: &str) -> Option<V> {
+        self.connections
+            .get_mut(&connection_key(connection))?
+            .entries
+            .get(key)
+            .cloned()
+    }
+
+    pub fn put(&mut self, connection: &Arc<C>, key: String, value: V) {
+        let capacity = self.capacity;
+        let connections = &mut self.connections;
+        let id = connection_key(connection);
+        if !connections.contains_key(&id) {
+            // Forget connections the pool has closed before tracking a new one
+            connections.retain(|_, entries| entries.connection.strong_count() > 0);
+        }
+
+        connections
+            .entry(id)
+            .or_insert_with(|| ConnectionEntries {
+                connection: Arc::downgrade(connection),
+                entries: LruCache::new(capacity),
+            })
+            .entries
+            .put(key, value);
+    }
+
+    pub fn remove(&mut self, connection: &Arc<C>, key: &str) {
+        if let Some(entries) = self.connections.get_mut(&connection_key(connection)) {
+            entries.entries.pop(key);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.connections.clear();
+    }
+
+    /// Number of connections that have entries, including closed ones not yet forgotten
+    pub fn tracked_connections(&self) -> usize {
+        self.connections.len()
+    }
+}
+
+fn connection_key<C>(connection: &Arc<C>) -> usize {
+    Arc::as_ptr(connection) as usize
+}
+
+/// Least recently used prepared statements, up to `capacity` per connection
+pub struct StatementCache {
+    statements: Mutex<ConnectionLru<deadpool_postgres::StatementCache, Statement>>,
+}
+
+impl StatementCache {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            statements: Mutex::new(ConnectionLru::new(capacity)),
+        }
+    }
+
+    /// Executes a query with a statement prepared on the client's connection
+    ///
+    /// A statement the server no longer has, or whose plan was invalidated by a table change,
+    /// is prepared again and retried once when the client isn't in a transaction.
+    pub async fn execute(
+        &self,
+        client: &impl PooledClient,
+        query: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<u64, Error> {
+        let statement = self.prepare(client, query).await?;
+
+        match client.client().execute(&statement, params).await {
+            Err(e) if is_stale(&e) => {
+                self.remove(client, query);
+                if !client.can_retry() {
+                    return Err(e);
+                }
+
+                debug!("Preparing stale statement again: {e}");
+                let statement = self.prepare(client, query).await?;
+                client.client().execute(&statement, params).await
+            }
+            result => result,
+        }
+    }
+
+    /// Drops every cached statement, so tables changed by a migration get fresh plans
+    pub fn clear(&self) {
+        self.statements.lock().unwrap().clear();
+    }
+
+    async fn prepare(&self, client: &impl PooledClient, query: &str) -> Result<Statement, Error> {
+        let cached = self
+            .statements
+            .lock()
+            .unwrap()
+            .get(client.connection(), query);
+        if let Some(statement) = cached {
+            return Ok(statement);
+        }
+
+        let statement = client.client().prepare(query).await?;
+        self.statements.lock().unwrap().put(
+            client.connection(),
+            query.to_string(),
+            statement.clone(),
+        );
+
+        Ok(statement)
+    }
+
+    fn remove(&self, client: &impl PooledClient, query: &str) {
+        self.statements
+            .lock()
+            .unwrap()
+            .remove(client.connection(), query);
+    }
+}
+
+// Errors after which a cached statement must be prepared again
+fn is_stale(error: &Error) -> bool {
+    error
+        .as_db_error()
+        .is_some_and(|db_error| is_stale_statement(db_error.code(), db_error.message()))
+}
+
+/// Whether a server error means a cached statement is gone or its plan no longer fits the table
+pub fn is_stale_statement(code: &SqlState, message: &str) -> bool {
+    *code == SqlState::INVALID_SQL_STATEMENT_NAME
+        || (*code == SqlState::FEATURE_NOT_SUPPORTED
+            && message == "cached plan must not change result type")
+}
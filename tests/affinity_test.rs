@@ -0,0 +1,34 @@
+use kafka_postgres_transform::affinity::{available_cores, split_cores};
+
+#[test]
+fn test_requested_cores_are_used_as_given() {
+    assert_eq!(available_cores(Some(&[3, 1, 2])), vec![3, 1, 2]);
+    assert!(available_cores(Some(&[])).is_empty());
+    assert!(!available_cores(None).is_empty());
+}
+
+#[test]
+fn test_workers_and_runtime_get_distinct_cores() {
+    let (workers, runtime) = split_cores(&[0, 1, 2, 3, 4, 5], 4);
+    assert_eq!(workers, vec![0, 1, 2, 3]);
+    assert_eq!(runtime, vec![4, 5]);
+}
+
+#[test]
+fn test_runtime_shares_cores_when_workers_take_them_all() {
+    let (workers, runtime) = split_cores(&[0, 1], 2);
+    assert_eq!(workers, vec![0, 1]);
+    assert_eq!(runtime, vec![0, 1]);
+
+    // More workers than cores: each core is used, and workers are pinned round-robin
+    let (workers, runtime) = split_cores(&[0, 1, 2], 8);
+    assert_eq!(workers, vec![0, 1, 2]);
+    assert_eq!(runtime, vec![0, 1, 2]);
+}
+
+#[test]
+fn test_no_cores_pins_nothing() {
+    let (workers, runtime) = split_cores(&[], 4);
+    assert!(workers.is_empty());
+    assert!(runtime.is_empty());
+}
@@ -0,0 +1,44 @@
+use anyhow::Result;
+use kafka_postgres_transform::deno::Column;
+use kafka_postgres_transform::postgres::ColumnData;
+use serde_json::json;
+
+fn column(definition: serde_json::Value) -> Result<Column> {
+    Ok(serde_json::from_value(definition)?)
+}
+
+#[test]
+fn test_columns_are_nullable_by_default() -> Result<()> {
+    let col = column(json!({ "name": "age", "type": "int" }))?;
+    assert!(
+        col.nullable,
+        "Columns should be nullable unless declared otherwise"
+    );
+    assert!(col.default.is_none());
+    Ok(())
+}
+
+#[test]
+fn test_nulls_become_none() -> Result<()> {
+    let col = column(json!({ "name": "age", "type": "int" }))?;
+    let seven = json!(7);
+
+    let data = ColumnData::from_values(&col, &[Some(&seven), None])?;
+    assert!(matches!(data, ColumnData::Int(values) if values == vec![Some(7), None]));
+    Ok(())
+}
+
+#[test]
+fn test_type_mismatch_names_the_column() -> Result<()> {
+    let col = column(json!({ "name": "active", "type": "bool", "nullable": false }))?;
+    let text = json!("yes");
+
+    let err = ColumnData::from_values(&col, &[Some(&text)])
+        .err()
+        .expect("Expected a type mismatch");
+    assert!(
+        err.to_string().contains("active"),
+        "Unexpected error: {err}"
+    );
+    Ok(())
+}
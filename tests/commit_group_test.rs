@@ -0,0 +1,182 @@
+use anyhow::Result;
+use kafka_postgres_transform::commit_group::CommitGroup;
+use kafka_postgres_transform::postgres::{Pool, PoolOptions};
+use std::time::Duration;
+
+fn grouping_pool() -> Result<Pool> {
+    // The pool connects lazily, so nothing here needs a running Postgres
+    Ok(Pool::new(
+        "postgres://postgres@localhost/postgres?sslmode=disable",
+        &PoolOptions::default(),
+    )?
+    .with_commit_grouping(Some(10), Some(Duration::from_millis(500))))
+}
+
+#[tokio::test]
+async fn test_new_group_has_no_open_transaction() -> Result<()> {
+    let pool = grouping_pool()?;
+    let group = CommitGroup::<()>::new(&pool);
+
+    assert!(group.is_empty());
+    assert_eq!(group.deadline(), None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_committing_empty_group_settles_nothing() -> Result<()> {
+    let pool = grouping_pool()?;
+    let mut group = CommitGroup::<u32>::new(&pool);
+
+    assert!(group.commit(&pool).await.is_empty());
+    Ok(())
+}
+
+mod test_db;
+
+#[tokio::test]
+async fn test_group_commits_after_every_batches() -> Result<()> {
+    let Some(url) = test_db::database_url("test_group_commits_after_every_batches") else {
+        return Ok(());
+    };
+    let table = "commit_group_every_batches";
+    test_db::create_table(&url, table, "id int4 PRIMARY KEY").await?;
+    let pool = test_db::pool(&url)?.with_commit_grouping(Some(3), None);
+    let mut group = CommitGroup::new(&pool);
+
+    assert!(
+        group
+            .write(&pool, 1, test_db::id_rows(table, &[1])?)
+            .await
+            .is_empty()
+    );
+    assert!(
+        group
+            .write(&pool, 2, test_db::id_rows(table, &[2, 3])?)
+            .await
+            .is_empty()
+    );
+    assert!(!group.is_empty());
+    assert!(
+        group.deadline().is_some(),
+        "A batch count alone still commits on a default interval"
+    );
+    assert!(
+        test_db::ids(&url, table).await?.is_empty(),
+        "Rows are not committed yet"
+    );
+
+    let settled = group.write(&pool, 3, test_db::id_rows(table, &[4])?).await;
+    let rows: Vec<_> = settled
+        .into_iter()
+        .map(|(tag, inserted)| Ok((tag, inserted?.rows)))
+        .collect::<Result<_>>()?;
+    assert_eq!(rows, vec![(1, 1), (2, 2), (3, 1)]);
+    assert!(group.is_empty());
+    assert_eq!(test_db::ids(&url, table).await?, vec![1, 2, 3, 4]);
+
+    test_db::drop_table(&url, table).await
+}
+
+#[tokio::test]
+async fn test_group_is_due_at_its_deadline() -> Result<()> {
+    let Some(url) = test_db::database_url("test_group_is_due_at_its_deadline") else {
+        return Ok(());
+    };
+    let table = "commit_group_deadline";
+    test_db::create_table(&url, table, "id int4 PRIMARY KEY").await?;
+    let interval = Duration::from_millis(200);
+    let pool = test_db::pool(&url)?.with_commit_grouping(None, Some(interval));
+    let mut group = CommitGroup::new(&pool);
+
+    assert!(
+        group
+            .write(&pool, 1, test_db::id_rows(table, &[1])?)
+            .await
+            .is_empty()
+    );
+    let deadline = group
+        .deadline()
+        .expect("An open transaction has a deadline");
+
+    // A write after the deadline commits along with everything before it
+    tokio::time::sleep_until(deadline).await;
+    let settled = group.write(&pool, 2, test_db::id_rows(table, &[2])?).await;
+    assert_eq!(settled.len(), 2);
+    assert_eq!(group.deadline(), None);
+    assert_eq!(test_db::ids(&url, table).await?, vec![1, 2]);
+
+    test_db::drop_table(&url, table).await
+}
+
+#[tokio::test]
+async fn test_failed_group_retries_each_batch_alone() -> Result<()> {
+    let Some(url) = test_db::database_url("test_failed_group_retries_each_batch_alone") else {
+        return Ok(());
+    };
+    let table = "commit_group_retry";
+    test_db::create_table(&url, table, "id int4 PRIMARY KEY").await?;
+    let pool = test_db::pool(&url)?.with_commit_grouping(Some(10), None);
+    let mut group = CommitGroup::new(&pool);
+
+    assert!(
+        group
+            .write(&pool, 1, test_db::id_rows(table, &[1])?)
+            .await
+            .is_empty()
+    );
+
+    // The duplicate aborts the group's transaction, so both batches are written again alone
+    let settled = group
+        .write(&pool, 2, test_db::id_rows(table, &[1, 2])?)
+        .await;
+    assert!(group.is_empty());
+    assert_eq!(settled.len(), 2);
+
+    let (tag, first) = &settled[0];
+    assert_eq!((*tag, first.as_ref().unwrap().rows), (1, 1));
+    let (tag, second) = &settled[1];
+    let second = second.as_ref().unwrap();
+    assert_eq!((*tag, second.rows), (2, 1));
+    assert_eq!(second.rejected.len(), 1);
+    assert_eq!(second.rejected[0].code, "23505");
+    assert_eq!(test_db::ids(&url, table).await?, vec![1, 2]);
+
+    test_db::drop_table(&url, table).await
+}
+
+#[tokio::test]
+async fn test_group_commits_before_a_migration() -> Result<()> {
+    let Some(url) = test_db::database_url("test_group_commits_before_a_migration") else {
+        return Ok(());
+    };
+    let (first, second) = ("commit_group_migrate_a", "commit_group_migrate_b");
+    test_db::drop_table(&url, first).await?;
+    test_db::drop_table(&url, second).await?;
+    let pool = test_db::pool(&url)?
+        .with_auto_migrate(true)
+        .with_commit_grouping(Some(10), None);
+    let mut group = CommitGroup::new(&pool);
+
+    assert!(
+        group
+            .write(&pool, 1, test_db::id_rows(first, &[1])?)
+            .await
+            .is_empty()
+    );
+
+    // A new table has to be created, which commits the open group first
+    let settled = group.write(&pool, 2, test_db::id_rows(second, &[2])?).await;
+    assert_eq!(settled.len(), 1);
+    assert_eq!(settled[0].0, 1);
+    assert_eq!(test_db::ids(&url, first).await?, vec![1]);
+    assert!(
+        !group.is_empty(),
+        "The second batch waits for the next commit"
+    );
+
+    assert_eq!(group.commit(&pool).await.len(), 1);
+    assert_eq!(test_db::ids(&url, second).await?, vec![2]);
+
+    test_db::drop_table(&url, first).await?;
+    test_db::drop_table(&url, second).await
+}
@@ -0,0 +1,157 @@
+use anyhow::Result;
+use kafka_postgres_transform::deno::TransformResult;
+use kafka_postgres_transform::postgres::insert_data;
+use serde_json::json;
+
+mod test_db;
+
+fn copied_upsert(table: &str, rows: serde_json::Value) -> Result<TransformResult> {
+    Ok(serde_json::from_value(json!({
+        "success": true,
+        "table_info": {
+            "name": table,
+            "schema": "public",
+            "columns": [
+                { "name": "key", "type": "int4", "nullable": false },
+                { "name": "value", "type": "text" }
+            ],
+            "conflict_columns": ["key"],
+            "load_method": "copy"
+        },
+        "data": rows
+    }))?)
+}
+
+#[tokio::test]
+async fn test_copy_upsert_leaves_identity_to_the_table() -> Result<()> {
+    let Some(url) = test_db::database_url("test_copy_upsert_leaves_identity_to_the_table") else {
+        return Ok(());
+    };
+    let table = "copy_upsert_identity";
+    test_db::create_table(
+        &url,
+        table,
+        "id bigint GENERATED ALWAYS AS IDENTITY, key int4 NOT NULL UNIQUE, value text",
+    )
+    .await?;
+    let pool = test_db::pool(&url)?;
+
+    let first = copied_upsert(
+        table,
+        json!([{ "key": 1, "value": "a" }, { "key": 2, "value": "b" }]),
+    )?;
+    let inserted = insert_data(&pool, &first).await?;
+    assert_eq!((inserted.rows, inserted.rejected.len()), (2, 0));
+
+    let second = copied_upsert(table, json!([{ "key": 2, "value": "c" }]))?;
+    assert_eq!(insert_data(&pool, &second).await?.rows, 1);
+
+    let client = test_db::connect(&url).await?;
+    let rows = client
+        .query(
+            &format!("SELECT key, value FROM public.{table} ORDER BY key"),
+            &[],
+        )
+        .await?;
+    let rows: Vec<(i32, String)> = rows.iter().map(|row| (row.get(0), row.get(1))).collect();
+    assert_eq!(rows, vec![(1, "a".to_string()), (2, "c".to_string())]);
+
+    test_db::drop_table(&url, table).await
+}
+
+#[tokio::test]
+async fn test_copy_falls_back_to_unnest_for_unknown_column_types() -> Result<()> {
+    let Some(url) =
+        test_db::database_url("test_copy_falls_back_to_unnest_for_unknown_column_types")
+    else {
+        return Ok(());
+    };
+    let table = "copy_unknown_type";
+    test_db::create_table(&url, table, "key int4 NOT NULL, value real").await?;
+    let pool = test_db::pool(&url)?;
+
+    let result: TransformResult = serde_json::from_value(json!({
+        "success": true,
+        "table_info": {
+            "name": table,
+            "schema": "public",
+            "columns": [
+                { "name": "key", "type": "int4", "nullable": false },
+                { "name": "value", "type": "float8" }
+            ],
+            "load_method": "copy"
+        },
+        "data": [{ "key": 1, "value": 1.5 }]
+    }))?;
+    let inserted = insert_data(&pool, &result).await?;
+    assert_eq!((inserted.rows, inserted.rejected.len()), (1, 0));
+
+    let client = test_db::connect(&url).await?;
+    let row = client
+        .query_one(&format!("SELECT value FROM public.{table}"), &[])
+        .await?;
+    assert_eq!(row.get::<_, f32>(0), 1.5);
+
+    test_db::drop_table(&url, table).await
+}
+
+#[tokio::test]
+async fn test_numeric_values_are_stored_exactly() -> Result<()> {
+    let Some(url) = test_db::database_url("test_numeric_values_are_stored_exactly") else {
+        return Ok(());
+    };
+    let table = "numeric_exact";
+    let pool = test_db::pool(&url)?;
+    let client = test_db::connect(&url).await?;
+    let values = [
+        "1234567890123456789012345678901234567890.123456789",
+        "-0.000000000000000000000000000000001",
+        "1.50",
+        "12e3",
+        "0",
+        "NaN",
+    ];
+
+    for load_method in ["insert", "copy"] {
+        test_db::create_table(&url, table, "id int4, value numeric").await?;
+        let rows: Vec<_> = values
+            .iter()
+            .enumerate()
+            .map(|(id, value)| json!({ "id": id, "value": value }))
+            .collect();
+        let result: TransformResult = serde_json::from_value(json!({
+            "success": true,
+            "table_info": {
+                "name": table,
+                "schema": "public",
+                "columns": [
+                    { "name": "id", "type": "int4" },
+                    { "name": "value", "type": "numeric" }
+                ],
+                "load_method": load_method
+            },
+            "data": rows
+        }))?;
+        assert_eq!(insert_data(&pool, &result).await?.rows, values.len() as u64);
+
+        // Compared in Postgres against the same text parsed as numeric, keeping the scale
+        let stored = client
+            .query(
+                &format!("SELECT value::text FROM public.{table} ORDER BY id"),
+                &[],
+            )
+            .await?;
+        let stored: Vec<String> = stored.iter().map(|row| row.get(0)).collect();
+        let expected = client
+            .query(
+                "SELECT value::numeric::text FROM unnest($1::text[]) WITH ORDINALITY AS v(value, i) ORDER BY i",
+                &[&values.to_vec()],
+            )
+            .await?;
+        let expected: Vec<String> = expected.iter().map(|row| row.get(0)).collect();
+        assert_eq!(stored, expected, "{load_method}");
+
+        test_db::drop_table(&url, table).await?;
+    }
+    Ok(())
+}
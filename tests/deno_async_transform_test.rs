@@ -0,0 +1,60 @@
+use anyhow::Result;
+use kafka_postgres_transform::deno;
+use serde_json::json;
+use serial_test::serial;
+use std::io::Write;
+use tempfile::NamedTempFile;
+
+const ASYNC_PLUGIN: &str = r#"
+async function transform(inputs) {
+  const rows = await Promise.all(
+    inputs.map(async (input) => ({ customer_id: input.id, customer_name: input.name }))
+  );
+
+  return {
+    success: true,
+    table_info: {
+      name: "customers",
+      schema: "public",
+      columns: [
+        { name: "customer_id", type: "integer" },
+        { name: "customer_name", type: "string" }
+      ]
+    },
+    data: rows
+  };
+}
+
+globalThis.transform = transform;
+"#;
+
+#[tokio::test]
+#[serial]
+async fn test_async_transform_is_awaited() -> Result<()> {
+    // Write the plugin to a temporary file
+    let mut plugin_file = NamedTempFile::new()?;
+    plugin_file.write_all(ASYNC_PLUGIN.as_bytes())?;
+
+    let pool = deno::DenoPool::new(plugin_file.path())?;
+
+    let result = pool
+        .execute(vec![
+            json!({ "id": 1, "name": "Customer One" }),
+            json!({ "id": 2, "name": "Customer Two" }),
+        ])
+        .await?;
+
+    // Verify the promise was resolved rather than stringified
+    assert!(result.success, "Async transform should succeed");
+    assert_eq!(
+        result.table_info.as_ref().map(|t| t.name.as_str()),
+        Some("customers"),
+        "Incorrect table name"
+    );
+
+    let data = result.data.expect("Missing data in result");
+    assert_eq!(data.len(), 2, "Expected 2 transformed rows");
+    assert_eq!(data[1]["customer_name"], json!("Customer Two"));
+
+    Ok(())
+}
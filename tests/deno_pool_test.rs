@@ -0,0 +1,542 @@
+use anyhow::Result;
+use kafka_postgres_transform::config::load_plugin_config;
+use kafka_postgres_transform::deno::{DenoPool, PluginOptions};
+use serde_json::json;
+use serial_test::serial;
+use std::io::Write;
+use std::time::Duration;
+use tempfile::NamedTempFile;
+
+// Plugin that never returns when asked to hang, and echoes its inputs otherwise
+const HANGING_PLUGIN: &str = r#"
+function transform(inputs) {
+  if (inputs.some((input) => input.hang)) {
+    while (true) {}
+  }
+
+  return {
+    success: true,
+    table_info: { name: "echo", schema: "public", columns: [] },
+    data: inputs
+  };
+}
+
+globalThis.transform = transform;
+"#;
+
+fn write_plugin(source: &str) -> Result<NamedTempFile> {
+    let mut plugin_file = NamedTempFile::new()?;
+    plugin_file.write_all(source.as_bytes())?;
+    Ok(plugin_file)
+}
+
+#[tokio::test]
+#[serial]
+async fn test_batch_timeout_terminates_and_recovers() -> Result<()> {
+    let plugin_file = write_plugin(HANGING_PLUGIN)?;
+
+    let pool = DenoPool::with_options(
+        plugin_file.path(),
+        PluginOptions {
+            batch_timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        },
+    )?;
+
+    // A batch that never finishes should fail with a timeout instead of hanging
+    let err = pool
+        .execute(vec![json!({ "hang": true })])
+        .await
+        .expect_err("Hanging batch should time out");
+    assert!(
+        err.to_string().contains("timed out"),
+        "Unexpected error: {err}"
+    );
+
+    // Later batches should still be processed by the recycled isolate
+    let result = pool.execute(vec![json!({ "id": 1 })]).await?;
+    assert!(result.success, "Batch after timeout should succeed");
+    assert_eq!(result.data.map(|d| d.len()), Some(1));
+
+    Ok(())
+}
+
+// Plugin that keeps allocating when asked to leak, and echoes its inputs otherwise
+const LEAKING_PLUGIN: &str = r#"
+const retained = [];
+
+function transform(inputs) {
+  if (inputs.some((input) => input.leak)) {
+    while (true) {
+      retained.push(new Array(100000).fill("leak"));
+    }
+  }
+
+  return {
+    success: true,
+    table_info: { name: "echo", schema: "public", columns: [] },
+    data: inputs
+  };
+}
+
+globalThis.transform = transform;
+"#;
+
+#[tokio::test]
+#[serial]
+async fn test_heap_limit_terminates_and_recovers() -> Result<()> {
+    let plugin_file = write_plugin(LEAKING_PLUGIN)?;
+
+    let pool = DenoPool::with_options(
+        plugin_file.path(),
+        PluginOptions {
+            max_heap_size: Some(64 * 1024 * 1024),
+            ..Default::default()
+        },
+    )?;
+
+    // Exhausting the heap should fail the batch rather than abort the process
+    let err = pool
+        .execute(vec![json!({ "leak": true })])
+        .await
+        .expect_err("Leaking batch should hit the heap limit");
+    assert!(
+        err.to_string().contains("heap limit"),
+        "Unexpected error: {err}"
+    );
+
+    // The worker should have been restarted with a fresh runtime
+    let result = pool.execute(vec![json!({ "id": 1 })]).await?;
+    assert!(result.success, "Batch after heap exhaustion should succeed");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_pool_fails_fast_when_plugin_cannot_load() -> Result<()> {
+    let plugin_file = write_plugin("throw new Error('broken plugin');")?;
+
+    let err = DenoPool::new(plugin_file.path())
+        .err()
+        .expect("Pool should not start with a broken plugin");
+    assert!(
+        format!("{err:#}").contains("broken plugin"),
+        "Unexpected error: {err:#}"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_pool_reports_worker_health() -> Result<()> {
+    let plugin_file = write_plugin(HANGING_PLUGIN)?;
+    let pool = DenoPool::new(plugin_file.path())?;
+
+    let health = pool.health();
+    assert!(!health.is_empty(), "Expected at least one worker");
+    assert!(
+        health
+            .iter()
+            .all(|worker| worker.alive && worker.restarts == 0),
+        "All workers should start healthy: {health:?}"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_pool_uses_configured_worker_count() -> Result<()> {
+    let plugin_file = write_plugin(HANGING_PLUGIN)?;
+    let pool = DenoPool::with_options(
+        plugin_file.path(),
+        PluginOptions {
+            worker_count: Some(2),
+            queue_capacity: Some(3),
+            ..Default::default()
+        },
+    )?;
+
+    let metrics = pool.metrics();
+    assert_eq!(metrics.workers.len(), 2, "Expected 2 workers");
+    assert_eq!(metrics.queue_capacity, 3, "Expected queue capacity of 3");
+    assert_eq!(metrics.queue_depth, 0, "Queue should start empty");
+
+    // Batches queued beyond the worker count are still all processed
+    let batches = (0..6).map(|i| pool.execute(vec![json!({ "id": i })]));
+    for result in futures::future::join_all(batches).await {
+        assert!(result?.success, "Queued batch should succeed");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_workers_start_from_snapshot() -> Result<()> {
+    // Top-level state must survive the snapshot for transform to use it
+    let plugin_file = write_plugin(
+        r#"
+const tableName = ["snap", "shot"].join("");
+
+function transform(inputs) {
+  return {
+    success: true,
+    table_info: { name: tableName, schema: "public", columns: [] },
+    data: inputs
+  };
+}
+
+globalThis.transform = transform;
+"#,
+    )?;
+
+    let pool = DenoPool::with_options(
+        plugin_file.path(),
+        PluginOptions {
+            startup_snapshot: true,
+            ..Default::default()
+        },
+    )?;
+
+    let result = pool.execute(vec![json!({ "id": 1 })]).await?;
+    assert!(result.success, "Snapshot worker should transform batches");
+    assert_eq!(
+        result.table_info.map(|t| t.name),
+        Some("snapshot".to_string())
+    );
+
+    Ok(())
+}
+
+// Plugin that buffers rows until flushed, tagging them with a prefix from its config
+const BUFFERING_PLUGIN: &str = r#"
+let prefix = null;
+const pending = [];
+const tableInfo = { name: "buffered", schema: "public", columns: [] };
+
+function init(config) {
+  prefix = config.prefix;
+}
+
+function transform(inputs) {
+  for (const input of inputs) {
+    pending.push({ id: `${prefix}-${input.id}` });
+  }
+  return { success: true, table_info: tableInfo, data: [] };
+}
+
+async function flush() {
+  if (pending.length === 0) {
+    return null;
+  }
+  return { success: true, table_info: tableInfo, data: pending.splice(0) };
+}
+
+Object.assign(globalThis, { init, transform, flush });
+"#;
+
+#[tokio::test]
+#[serial]
+async fn test_lifecycle_hooks_init_and_flush() -> Result<()> {
+    let plugin_file = write_plugin(BUFFERING_PLUGIN)?;
+
+    let pool = DenoPool::with_options(
+        plugin_file.path(),
+        PluginOptions {
+            worker_count: Some(1),
+            config: json!({ "prefix": "row" }),
+            ..Default::default()
+        },
+    )?;
+
+    let result = pool
+        .execute(vec![json!({ "id": 1 }), json!({ "id": 2 })])
+        .await?;
+    assert_eq!(
+        result.data.map(|d| d.len()),
+        Some(0),
+        "Rows should be buffered"
+    );
+
+    // Flushing returns the buffered rows, built with the config passed to init
+    let flushed = pool.flush().await;
+    assert!(
+        flushed.errors.is_empty(),
+        "Flush failed: {:?}",
+        flushed.errors
+    );
+    assert_eq!(flushed.results.len(), 1, "Expected one flush result");
+    let data = flushed.results[0]
+        .data
+        .clone()
+        .expect("Missing flushed data");
+    assert_eq!(
+        data,
+        vec![json!({ "id": "row-1" }), json!({ "id": "row-2" })]
+    );
+
+    // Nothing is left to flush afterwards
+    assert!(
+        pool.flush().await.results.is_empty(),
+        "Second flush should be empty"
+    );
+
+    Ok(())
+}
+
+// Plugin whose flush fails unless it has rows buffered
+const FAILING_FLUSH_PLUGIN: &str = r#"
+const tableInfo = { name: "rows", schema: "public", columns: [{ name: "id", type: "int4" }] };
+const pending = [];
+
+function transform(inputs) {
+  pending.push(...inputs);
+  return { success: true, table_info: tableInfo, data: [] };
+}
+
+function flush() {
+  if (pending.length === 0) {
+    throw new Error("nothing to flush");
+  }
+  return { success: true, table_info: tableInfo, data: pending.splice(0) };
+}
+
+Object.assign(globalThis, { transform, flush });
+"#;
+
+#[tokio::test]
+#[serial]
+async fn test_flush_keeps_rows_when_another_worker_fails() -> Result<()> {
+    let plugin_file = write_plugin(FAILING_FLUSH_PLUGIN)?;
+
+    let pool = DenoPool::with_options(
+        plugin_file.path(),
+        PluginOptions {
+            worker_count: Some(2),
+            ..Default::default()
+        },
+    )?;
+
+    // Only the worker that ran the batch has rows, the other one's flush throws
+    pool.execute(vec![json!({ "id": 1 })]).await?;
+    let flushed = pool.flush().await;
+
+    assert_eq!(flushed.results.len(), 1, "Buffered rows should be returned");
+    assert_eq!(
+        flushed.errors.len(),
+        1,
+        "The failing worker should be reported"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_hanging_init_times_out() -> Result<()> {
+    let plugin_file = write_plugin(
+        r#"
+function init() { while (true) {} }
+function transform(inputs) { return { success: true, data: [] }; }
+Object.assign(globalThis, { init, transform });
+"#,
+    )?;
+
+    let started = std::time::Instant::now();
+    let pool = DenoPool::with_options(
+        plugin_file.path(),
+        PluginOptions {
+            worker_count: Some(1),
+            batch_timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        },
+    );
+
+    assert!(pool.is_err(), "A hanging init should fail the worker");
+    assert!(started.elapsed() < Duration::from_secs(5));
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_hanging_flush_times_out_and_restarts_isolate() -> Result<()> {
+    let plugin_file = write_plugin(
+        r#"
+const tableInfo = { name: "rows", schema: "public", columns: [{ name: "id", type: "int4" }] };
+function transform(inputs) { return { success: true, table_info: tableInfo, data: inputs }; }
+function flush() { while (true) {} }
+Object.assign(globalThis, { transform, flush });
+"#,
+    )?;
+
+    let pool = DenoPool::with_options(
+        plugin_file.path(),
+        PluginOptions {
+            worker_count: Some(1),
+            batch_timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        },
+    )?;
+
+    let flushed = tokio::time::timeout(Duration::from_secs(5), pool.flush()).await?;
+    assert_eq!(flushed.errors.len(), 1, "The hanging flush should fail");
+
+    // The worker replaced its isolate and keeps transforming
+    let result = pool.execute(vec![json!({ "id": 1 })]).await?;
+    assert_eq!(result.data.map(|d| d.len()), Some(1));
+    Ok(())
+}
+
+// Plugin that reads the frozen global config and reports whether it could be modified
+const CONFIG_PLUGIN: &str = r#"
+function transform(inputs) {
+  try {
+    config.env.PLUGIN_TEST_API_TOKEN = "overwritten";
+  } catch (_) {}
+
+  return {
+    success: true,
+    table_info: { name: "configured", schema: "public", columns: [] },
+    data: inputs.map((input) => ({ id: input.id, table: config.table, token: config.env.PLUGIN_TEST_API_TOKEN }))
+  };
+}
+
+globalThis.transform = transform;
+"#;
+
+#[tokio::test]
+#[serial]
+async fn test_plugin_reads_frozen_global_config() -> Result<()> {
+    let plugin_file = write_plugin(CONFIG_PLUGIN)?;
+
+    let mut config_file = tempfile::Builder::new().suffix(".toml").tempfile()?;
+    config_file.write_all(b"table = \"orders\"\n")?;
+
+    // SAFETY: tests touching the environment run serially
+    unsafe { std::env::set_var("PLUGIN_TEST_API_TOKEN", "secret") };
+    let config = load_plugin_config(
+        Some(config_file.path()),
+        &["PLUGIN_TEST_API_TOKEN".to_string()],
+    )?;
+
+    let pool = DenoPool::with_options(
+        plugin_file.path(),
+        PluginOptions {
+            worker_count: Some(1),
+            config,
+            ..Default::default()
+        },
+    )?;
+
+    let result = pool.execute(vec![json!({ "id": 1 })]).await?;
+    assert_eq!(
+        result.data,
+        Some(vec![
+            json!({ "id": 1, "table": "orders", "token": "secret" })
+        ])
+    );
+
+    Ok(())
+}
+
+// Plugin that reports an outcome for every input instead of failing the batch
+const OUTCOME_PLUGIN: &str = r#"
+function transform(inputs) {
+  const outcomes = inputs.map((input, index) => {
+    if (input.skip) {
+      return { index, status: "skipped", reason: "Asked to skip" };
+    }
+    if (input.id === undefined) {
+      return { index, status: "failed", error: "Missing id" };
+    }
+    return { index, status: "ok", rows: [{ id: input.id }] };
+  });
+
+  return {
+    success: true,
+    table_info: { name: "outcomes", schema: "public", columns: [] },
+    outcomes
+  };
+}
+
+globalThis.transform = transform;
+"#;
+
+#[tokio::test]
+#[serial]
+async fn test_per_input_outcomes() -> Result<()> {
+    let plugin_file = write_plugin(OUTCOME_PLUGIN)?;
+    let pool = DenoPool::with_options(
+        plugin_file.path(),
+        PluginOptions {
+            worker_count: Some(1),
+            ..Default::default()
+        },
+    )?;
+
+    let result = pool
+        .execute(vec![
+            json!({ "id": 1 }),
+            json!({ "name": "no id" }),
+            json!({ "id": 3, "skip": true }),
+            json!({ "id": 4 }),
+        ])
+        .await?;
+
+    // Rows of ok outcomes are inserted even though another input failed
+    let rows: Vec<_> = result.rows().cloned().collect();
+    assert_eq!(rows, vec![json!({ "id": 1 }), json!({ "id": 4 })]);
+
+    // The failure is linked back to its index and original input
+    let failures: Vec<_> = result.failures().collect();
+    assert_eq!(failures.len(), 1, "Expected one failed input");
+    assert_eq!(failures[0].0, 1);
+    assert_eq!(failures[0].1, "Missing id");
+    assert_eq!(failures[0].2, Some(&json!({ "name": "no id" })));
+
+    Ok(())
+}
+
+// Plugin that fans each order out to a header table and a line items table
+const FAN_OUT_PLUGIN: &str = r#"
+const ordersTable = { name: "orders", schema: "public", columns: [] };
+const lineItemsTable = { name: "line_items", schema: "public", columns: [] };
+
+function transform(inputs) {
+  return {
+    success: true,
+    tables: [
+      { table_info: ordersTable, rows: inputs.map((order) => ({ id: order.id })) },
+      {
+        table_info: lineItemsTable,
+        rows: inputs.flatMap((order) => order.items.map((sku) => ({ order_id: order.id, sku })))
+      }
+    ]
+  };
+}
+
+globalThis.transform = transform;
+"#;
+
+#[tokio::test]
+#[serial]
+async fn test_transform_emits_multiple_tables() -> Result<()> {
+    let plugin_file = write_plugin(FAN_OUT_PLUGIN)?;
+    let pool = DenoPool::new(plugin_file.path())?;
+
+    let result = pool
+        .execute(vec![json!({ "id": 1, "items": ["a", "b"] })])
+        .await?;
+
+    assert!(result.table_info.is_none(), "Expected only table groups");
+    let tables: Vec<_> = result
+        .tables
+        .iter()
+        .map(|group| (group.table_info.name.as_str(), group.rows.len()))
+        .collect();
+    assert_eq!(tables, vec![("orders", 1), ("line_items", 2)]);
+
+    Ok(())
+}
@@ -0,0 +1,77 @@
+use anyhow::Result;
+use kafka_postgres_transform::deno::{Column, TableInfo};
+use kafka_postgres_transform::migrate::{column_type, create_table_statement};
+use serde_json::json;
+
+fn table_info(value: serde_json::Value) -> Result<TableInfo> {
+    Ok(serde_json::from_value(value)?)
+}
+
+fn column(r#type: &str) -> Result<Column> {
+    Ok(serde_json::from_value(
+        json!({ "name": "c", "type": r#type }),
+    )?)
+}
+
+#[test]
+fn test_create_table_quotes_names_and_marks_not_null() -> Result<()> {
+    let table = table_info(json!({
+        "name": "Order Items",
+        "schema": "sales",
+        "columns": [
+            { "name": "id", "type": "bigint", "nullable": false },
+            { "name": "say \"hi\"", "type": "text" }
+        ]
+    }))?;
+
+    assert_eq!(
+        create_table_statement(&table)?,
+        "CREATE TABLE IF NOT EXISTS \"sales\".\"Order Items\" \
+         (\"id\" int8 NOT NULL, \"say \"\"hi\"\"\" text)"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_create_table_constraints() -> Result<()> {
+    let same = table_info(json!({
+        "name": "orders",
+        "schema": "public",
+        "columns": [{ "name": "id", "type": "int", "nullable": false }],
+        "primary_key": ["id"],
+        "conflict_columns": ["id"]
+    }))?;
+    assert_eq!(
+        create_table_statement(&same)?,
+        "CREATE TABLE IF NOT EXISTS \"public\".\"orders\" \
+         (\"id\" int4 NOT NULL, PRIMARY KEY (\"id\"))"
+    );
+
+    let different = table_info(json!({
+        "name": "orders",
+        "schema": "public",
+        "columns": [
+            { "name": "id", "type": "int", "nullable": false },
+            { "name": "sku", "type": "text", "nullable": false }
+        ],
+        "primary_key": ["id"],
+        "conflict_columns": ["sku"]
+    }))?;
+    assert_eq!(
+        create_table_statement(&different)?,
+        "CREATE TABLE IF NOT EXISTS \"public\".\"orders\" \
+         (\"id\" int4 NOT NULL, \"sku\" text NOT NULL, PRIMARY KEY (\"id\"), UNIQUE (\"sku\"))"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_column_types() -> Result<()> {
+    assert_eq!(column_type(&column("integer")?)?, "int4");
+    assert_eq!(column_type(&column("decimal")?)?, "numeric");
+    assert_eq!(column_type(&column("string")?)?, "text");
+    assert_eq!(column_type(&column("int[]")?)?, "int4[]");
+    assert_eq!(column_type(&column("timestamptz[]")?)?, "timestamptz[]");
+    assert!(column_type(&column("money")?).is_err());
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use anyhow::Result;
+use kafka_postgres_transform::deno::TableInfo;
+use kafka_postgres_transform::postgres::{on_conflict_clause, quote_ident};
+use serde_json::{Value, json};
+
+fn table_info(extra: Value) -> Result<TableInfo> {
+    let mut table = json!({
+        "name": "orders",
+        "schema": "public",
+        "columns": [
+            { "name": "order_id", "type": "text" },
+            { "name": "status", "type": "text" },
+            { "name": "version", "type": "int" }
+        ]
+    });
+    table
+        .as_object_mut()
+        .unwrap()
+        .extend(extra.as_object().unwrap().clone());
+
+    Ok(serde_json::from_value(table)?)
+}
+
+#[test]
+fn test_plain_insert_without_conflict_target() -> Result<()> {
+    assert_eq!(on_conflict_clause(&table_info(json!({}))?)?, "");
+    Ok(())
+}
+
+#[test]
+fn test_primary_key_defaults_to_update_all() -> Result<()> {
+    let table = table_info(json!({ "primary_key": "order_id" }))?;
+    assert_eq!(
+        on_conflict_clause(&table)?,
+        r#" ON CONFLICT ("order_id") DO UPDATE SET "status" = EXCLUDED."status", "version" = EXCLUDED."version""#
+    );
+    Ok(())
+}
+
+#[test]
+fn test_conflict_policies() -> Result<()> {
+    let do_nothing = table_info(json!({ "on_conflict": { "action": "do_nothing" } }))?;
+    assert_eq!(on_conflict_clause(&do_nothing)?, " ON CONFLICT DO NOTHING");
+
+    let listed = table_info(json!({
+        "conflict_columns": ["order_id"],
+        "on_conflict": { "action": "update", "columns": ["status"] }
+    }))?;
+    assert_eq!(
+        on_conflict_clause(&listed)?,
+        r#" ON CONFLICT ("order_id") DO UPDATE SET "status" = EXCLUDED."status""#
+    );
+
+    let newer = table_info(json!({
+        "primary_key": ["order_id"],
+        "on_conflict": { "action": "update_if_newer", "version_column": "version" }
+    }))?;
+    assert_eq!(
+        on_conflict_clause(&newer)?,
+        concat!(
+            r#" ON CONFLICT ("order_id") DO UPDATE SET "status" = EXCLUDED."status", "#,
+            r#""version" = EXCLUDED."version" WHERE "public"."orders"."version" < EXCLUDED."version""#
+        )
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_update_requires_conflict_target() -> Result<()> {
+    let table = table_info(json!({ "on_conflict": { "action": "update_all" } }))?;
+    assert!(on_conflict_clause(&table).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_update_columns_must_be_declared() -> Result<()> {
+    let listed = table_info(json!({
+        "primary_key": ["order_id"],
+        "on_conflict": { "action": "update", "columns": ["status", "updated_at"] }
+    }))?;
+    let err = on_conflict_clause(&listed).unwrap_err();
+    assert!(
+        err.to_string().contains("updated_at"),
+        "unexpected error: {err}"
+    );
+
+    let newer = table_info(json!({
+        "primary_key": ["order_id"],
+        "on_conflict": { "action": "update_if_newer", "version_column": "revision" }
+    }))?;
+    let err = on_conflict_clause(&newer).unwrap_err();
+    assert!(
+        err.to_string().contains("revision"),
+        "unexpected error: {err}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_identifiers_are_quoted() -> Result<()> {
+    assert_eq!(quote_ident("Orders")?, r#""Orders""#);
+    assert_eq!(
+        quote_ident(r#"x"; DROP TABLE users; --"#)?,
+        r#""x""; DROP TABLE users; --""#
+    );
+    assert!(quote_ident("").is_err());
+    assert!(quote_ident(&"a".repeat(64)).is_err());
+    Ok(())
+}
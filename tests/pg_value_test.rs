@@ -0,0 +1,126 @@
+use kafka_postgres_transform::pg_value::{self, ColumnType, Interval, ScalarType};
+use serde_json::json;
+
+#[test]
+fn test_parse_column_types() {
+    assert_eq!(
+        ColumnType::parse("decimal"),
+        Some(ColumnType::Scalar(ScalarType::Numeric))
+    );
+    assert_eq!(
+        ColumnType::parse("timestamptz[]"),
+        Some(ColumnType::Array(ScalarType::TimestampTz))
+    );
+    assert_eq!(ColumnType::parse("geometry"), None);
+}
+
+#[test]
+fn test_integers_are_range_checked() {
+    assert_eq!(pg_value::to_i32(&json!(7)), Some(7));
+    assert_eq!(pg_value::to_i32(&json!(3_000_000_000i64)), None);
+    assert_eq!(pg_value::to_i16(&json!(40_000)), None);
+    // Protobuf encodes 64-bit integers as strings
+    assert_eq!(
+        pg_value::to_i64(&json!("9007199254740993")),
+        Some(9_007_199_254_740_993)
+    );
+}
+
+#[test]
+fn test_numeric_keeps_string_precision() {
+    let long = "1234567890123456789012345678901234567890.123456789";
+    assert_eq!(
+        pg_value::to_numeric(&json!(long)).unwrap().to_string(),
+        long
+    );
+    assert_eq!(
+        pg_value::to_numeric(&json!(19.99)).unwrap().to_string(),
+        "19.99"
+    );
+    for valid in ["NaN", "-Infinity", "1.5e-3", ".5", "-0", "+12."] {
+        assert!(pg_value::to_numeric(&json!(valid)).is_some(), "{valid}");
+    }
+    for invalid in [
+        "",
+        "-",
+        ".",
+        "1e",
+        "1.2.3",
+        "12abc",
+        "-NaN",
+        "1e99999999999",
+    ] {
+        assert!(pg_value::to_numeric(&json!(invalid)).is_none(), "{invalid}");
+    }
+    assert!(pg_value::to_numeric(&json!(true)).is_none());
+}
+
+#[test]
+fn test_temporal_coercions() {
+    let from_rfc3339 = pg_value::to_timestamptz(&json!("2024-05-01T12:00:00+02:00")).unwrap();
+    let from_millis = pg_value::to_timestamptz(&json!(1_714_557_600_000i64)).unwrap();
+    assert_eq!(from_rfc3339, from_millis);
+
+    assert!(pg_value::to_timestamp(&json!("2024-05-01 10:00:00.5")).is_some());
+    assert!(pg_value::to_date(&json!("2024-05-01")).is_some());
+    assert!(pg_value::to_time(&json!("23:59")).is_some());
+    assert!(pg_value::to_date(&json!("May 1st")).is_none());
+}
+
+#[test]
+fn test_interval_parsing() {
+    assert_eq!(
+        Interval::parse_iso8601("P1Y2M3DT4H5M6.5S"),
+        Some(Interval {
+            months: 14,
+            days: 3,
+            microseconds: 4 * 3_600_000_000 + 5 * 60_000_000 + 6_500_000,
+        })
+    );
+    assert_eq!(
+        Interval::parse_iso8601("-P2W"),
+        Some(Interval {
+            months: 0,
+            days: -14,
+            microseconds: 0,
+        })
+    );
+    assert_eq!(Interval::parse_iso8601("P"), None);
+    assert_eq!(Interval::parse_iso8601("P1H"), None);
+    assert_eq!(Interval::parse_iso8601("P999999999Y"), None);
+    assert_eq!(Interval::parse_iso8601("PT9999999999H"), None);
+    assert_eq!(Interval::parse_iso8601("P2147483647DT1H1D"), None);
+    assert_eq!(Interval::parse_iso8601("PT99999999999999999999S"), None);
+    assert_eq!(
+        pg_value::to_interval(&json!(1.5)),
+        Some(Interval::from_seconds(1.5))
+    );
+}
+
+#[test]
+fn test_bytea_uuid_and_inet() {
+    assert_eq!(
+        pg_value::to_bytea(&json!("aGVsbG8=")),
+        Some(b"hello".to_vec())
+    );
+    assert!(pg_value::to_uuid(&json!("67e55044-10b1-426f-9247-bb680e5fe0c8")).is_some());
+    assert!(pg_value::to_inet(&json!("2001:db8::1")).is_some());
+    assert!(pg_value::to_inet(&json!("10.0.0.0/8")).is_none());
+}
+
+#[test]
+fn test_array_literals() {
+    assert_eq!(
+        pg_value::to_array_literal(ScalarType::Int, &json!([1, null, 3])),
+        Some(r#"{"1",NULL,"3"}"#.to_string())
+    );
+    assert_eq!(
+        pg_value::to_array_literal(ScalarType::Text, &json!(["a \"quoted\" \\ value"])),
+        Some(r#"{"a \"quoted\" \\ value"}"#.to_string())
+    );
+    assert_eq!(
+        pg_value::to_array_literal(ScalarType::Int, &json!([1, "two"])),
+        None
+    );
+    assert_eq!(pg_value::to_array_literal(ScalarType::Int, &json!(1)), None);
+}
@@ -0,0 +1,101 @@
+use anyhow::Result;
+use kafka_postgres_transform::deno::{DenoPool, PluginOptions};
+use serde_json::json;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use tempfile::Builder;
+use tracing_subscriber::fmt::MakeWriter;
+
+// Plugin that writes to the console at every level while transforming
+const LOGGING_PLUGIN: &str = r#"
+function transform(inputs) {
+  console.debug("debug message");
+  console.log("log message", inputs.length);
+  console.warn("warn message");
+  console.error("error message");
+
+  return {
+    success: true,
+    table_info: { name: "logged", schema: "public", columns: [] },
+    data: inputs
+  };
+}
+
+globalThis.transform = transform;
+"#;
+
+// Collects formatted log lines so the test can inspect them
+#[derive(Clone, Default)]
+struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+impl Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for CapturedLogs {
+    type Writer = CapturedLogs;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+#[tokio::test]
+async fn test_console_output_is_routed_to_tracing() -> Result<()> {
+    let logs = CapturedLogs::default();
+    // Workers run on their own threads, so the subscriber has to be global
+    tracing_subscriber::fmt()
+        .with_writer(logs.clone())
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .init();
+
+    let mut plugin_file = Builder::new()
+        .prefix("logging_plugin")
+        .suffix(".js")
+        .tempfile()?;
+    plugin_file.write_all(LOGGING_PLUGIN.as_bytes())?;
+
+    let pool = DenoPool::with_options(
+        plugin_file.path(),
+        PluginOptions {
+            worker_count: Some(1),
+            ..Default::default()
+        },
+    )?;
+    pool.execute(vec![json!({ "id": 1 })]).await?;
+
+    let output = String::from_utf8(logs.0.lock().unwrap().clone())?;
+    let plugin_lines: Vec<&str> = output
+        .lines()
+        .filter(|line| line.contains("plugin:"))
+        .collect();
+
+    for (level, message) in [
+        ("DEBUG", "debug message"),
+        ("INFO", "log message 1"),
+        ("WARN", "warn message"),
+        ("ERROR", "error message"),
+    ] {
+        let line = plugin_lines
+            .iter()
+            .find(|line| line.contains(message))
+            .unwrap_or_else(|| panic!("Missing plugin log line for {message:?} in {output}"));
+        assert!(line.contains(level), "Expected {level} in {line}");
+        assert!(
+            line.contains("plugin=logging_plugin"),
+            "Missing plugin in {line}"
+        );
+        assert!(line.contains("worker=0"), "Missing worker in {line}");
+        assert!(line.contains("batch="), "Missing batch in {line}");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,99 @@
+use anyhow::Result;
+use kafka_postgres_transform::deno::PluginOptions;
+use kafka_postgres_transform::reload::ReloadablePool;
+use serde_json::json;
+use serial_test::serial;
+use std::fs;
+use tempfile::tempdir;
+
+fn plugin_source(table: &str) -> String {
+    format!(
+        r#"
+function transform(inputs) {{
+  return {{
+    success: true,
+    table_info: {{ name: "{table}", schema: "public", columns: [] }},
+    data: inputs
+  }};
+}}
+
+globalThis.transform = transform;
+"#
+    )
+}
+
+#[tokio::test]
+#[serial]
+async fn test_reload_swaps_plugin_and_keeps_old_on_failure() -> Result<()> {
+    let temp_dir = tempdir()?;
+    let plugin_path = temp_dir.path().join("transform.js");
+    fs::write(&plugin_path, plugin_source("first"))?;
+
+    let options = PluginOptions {
+        worker_count: Some(1),
+        ..Default::default()
+    };
+    let pool = ReloadablePool::new(&plugin_path, options)?;
+
+    let result = pool.execute(vec![json!({ "id": 1 })]).await?;
+    assert_eq!(result.table_info.map(|t| t.name), Some("first".to_string()));
+
+    // Updated code is picked up by the reload
+    fs::write(&plugin_path, plugin_source("second"))?;
+    pool.reload().await?;
+
+    let result = pool.execute(vec![json!({ "id": 2 })]).await?;
+    assert_eq!(
+        result.table_info.map(|t| t.name),
+        Some("second".to_string())
+    );
+
+    // A broken plugin is rejected and the previous one keeps running
+    fs::write(&plugin_path, "function transform(inputs) {")?;
+    assert!(
+        pool.reload().await.is_err(),
+        "Reload of a broken plugin should fail"
+    );
+
+    let result = pool.execute(vec![json!({ "id": 3 })]).await?;
+    assert_eq!(
+        result.table_info.map(|t| t.name),
+        Some("second".to_string())
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_reload_rejects_plugin_returning_no_result() -> Result<()> {
+    let temp_dir = tempdir()?;
+    let plugin_path = temp_dir.path().join("transform.js");
+    fs::write(&plugin_path, plugin_source("first"))?;
+
+    let options = PluginOptions {
+        worker_count: Some(1),
+        ..Default::default()
+    };
+    let pool = ReloadablePool::new(&plugin_path, options)?;
+
+    fs::write(&plugin_path, "globalThis.transform = () => 42;")?;
+    let error = pool.reload().await.unwrap_err();
+    assert!(
+        format!("{error:#}").contains("smoke transform"),
+        "Unexpected error: {error:#}"
+    );
+
+    let result = pool.execute(vec![json!({ "id": 1 })]).await?;
+    assert_eq!(result.table_info.map(|t| t.name), Some("first".to_string()));
+
+    // Failing a batch with nothing in it is still a valid result
+    fs::write(
+        &plugin_path,
+        r#"globalThis.transform = () => ({ success: false, error: "nothing to do" });"#,
+    )?;
+    pool.reload().await?;
+    assert!(!pool.execute(vec![json!({ "id": 2 })]).await?.success);
+
+    Ok(())
+}
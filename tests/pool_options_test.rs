@@ -0,0 +1,55 @@
+use anyhow::Result;
+use deadpool_postgres::RecyclingMethod;
+use kafka_postgres_transform::postgres::{
+    Pool, PoolOptions, parse_recycling_method, parse_session_setting,
+};
+use std::time::Duration;
+
+#[test]
+fn test_parse_session_setting() -> Result<()> {
+    assert_eq!(
+        parse_session_setting("statement_timeout=30s")?,
+        ("statement_timeout".to_string(), "30s".to_string())
+    );
+    assert_eq!(
+        parse_session_setting("search_path = app, public")?,
+        ("search_path".to_string(), "app, public".to_string())
+    );
+    assert!(parse_session_setting("synchronous_commit").is_err());
+    assert!(parse_session_setting("=off").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_parse_recycling_method() -> Result<()> {
+    assert_eq!(parse_recycling_method("fast")?, RecyclingMethod::Fast);
+    assert_eq!(
+        parse_recycling_method("verified")?,
+        RecyclingMethod::Verified
+    );
+    assert_eq!(parse_recycling_method("clean")?, RecyclingMethod::Clean);
+    assert!(parse_recycling_method("thorough").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_pool_is_built_without_connecting() -> Result<()> {
+    let options = PoolOptions {
+        max_size: Some(4),
+        wait_timeout: Some(Duration::from_millis(500)),
+        create_timeout: Some(Duration::from_secs(2)),
+        session_settings: vec![("application_name".to_string(), "test".to_string())],
+        ..PoolOptions::default()
+    };
+
+    Pool::new(
+        "postgres://postgres@localhost/postgres?sslmode=disable",
+        &options,
+    )?;
+    Ok(())
+}
+
+#[test]
+fn test_invalid_connection_string_is_an_error() {
+    assert!(Pool::new("not a connection string", &PoolOptions::default()).is_err());
+}
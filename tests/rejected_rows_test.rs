@@ -0,0 +1,279 @@
+use anyhow::{Result, anyhow};
+use kafka_postgres_transform::deno::TransformResult;
+use kafka_postgres_transform::postgres::{RangeWriter, RejectedRow, bisect, insert_data};
+use serde_json::json;
+use std::collections::HashMap;
+use std::ops::Range;
+
+mod test_db;
+
+// Rejects any range holding one of its bad rows with that row's SQLSTATE, and records writes
+#[derive(Default)]
+struct FakeWriter {
+    bad: HashMap<usize, &'static str>,
+    // Fails every write after this many with an error that isn't about the rows
+    fail_after: Option<usize>,
+    writes: Vec<Range<usize>>,
+}
+
+impl FakeWriter {
+    fn rejecting(bad: &[(usize, &'static str)]) -> Self {
+        Self {
+            bad: bad.iter().copied().collect(),
+            ..Self::default()
+        }
+    }
+}
+
+impl RangeWriter for FakeWriter {
+    async fn write(&mut self, rows: Range<usize>) -> Result<u64> {
+        self.writes.push(rows.clone());
+        if self
+            .fail_after
+            .is_some_and(|writes| self.writes.len() > writes)
+        {
+            return Err(anyhow!("connection closed"));
+        }
+        match rows.clone().find_map(|row| self.bad.get(&row)) {
+            Some(code) => Err(anyhow!("rejected {code}")),
+            None => Ok(rows.len() as u64),
+        }
+    }
+
+    fn row_error_code(&self, error: &anyhow::Error) -> Option<String> {
+        error
+            .to_string()
+            .strip_prefix("rejected ")
+            .map(str::to_string)
+    }
+}
+
+fn rejected_positions(rejected: &[(usize, anyhow::Error)]) -> Vec<(usize, String)> {
+    rejected
+        .iter()
+        .map(|(position, error)| (*position, error.to_string()))
+        .collect()
+}
+
+#[test]
+fn test_outcome_rows_keep_their_input_index() -> Result<()> {
+    let result: TransformResult = serde_json::from_value(json!({
+        "success": true,
+        "table_info": null,
+        "data": [{ "id": 1 }],
+        "error": null,
+        "outcomes": [
+            { "index": 0, "status": "ok", "rows": [{ "id": 2 }, { "id": 3 }] },
+            { "index": 1, "status": "failed", "error": "bad input" },
+            { "index": 2, "status": "ok", "rows": [{ "id": 4 }] }
+        ]
+    }))?;
+
+    let inputs: Vec<_> = result.indexed_rows().map(|(input, _)| input).collect();
+    assert_eq!(inputs, vec![None, Some(0), Some(0), Some(2)]);
+    assert_eq!(result.rows().count(), 4);
+    Ok(())
+}
+
+#[test]
+fn test_table_group_rows_keep_their_input_index() -> Result<()> {
+    let result: TransformResult = serde_json::from_value(json!({
+        "success": true,
+        "tables": [
+            {
+                "table_info": { "name": "a", "schema": "public", "columns": [] },
+                "rows": [{ "id": 1 }, { "id": 2 }],
+                "inputs": [3, 5]
+            },
+            {
+                "table_info": { "name": "b", "schema": "public", "columns": [] },
+                "rows": [{ "id": 1 }]
+            }
+        ]
+    }))?;
+
+    let inputs: Vec<_> = result.tables[0]
+        .indexed_rows()
+        .map(|(input, _)| input)
+        .collect();
+    assert_eq!(inputs, vec![Some(3), Some(5)]);
+    let inputs: Vec<_> = result.tables[1]
+        .indexed_rows()
+        .map(|(input, _)| input)
+        .collect();
+    assert_eq!(inputs, vec![None]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rejected_group_row_reports_its_input() -> Result<()> {
+    let Some(url) = test_db::database_url("test_rejected_group_row_reports_its_input") else {
+        return Ok(());
+    };
+    let table = "rejected_group_input";
+    test_db::create_table(&url, table, "id int4 PRIMARY KEY").await?;
+    let pool = test_db::pool(&url)?;
+
+    let result: TransformResult = serde_json::from_value(json!({
+        "success": true,
+        "tables": [{
+            "table_info": {
+                "name": table,
+                "schema": "public",
+                "columns": [{ "name": "id", "type": "int4", "nullable": false }]
+            },
+            "rows": [{ "id": 1 }, { "id": 2 }, { "id": 1 }],
+            "inputs": [4, 5, 7]
+        }]
+    }))?;
+    let inserted = insert_data(&pool, &result).await?;
+
+    assert_eq!(inserted.rows, 2);
+    assert_eq!(inserted.rejected.len(), 1);
+    assert_eq!(
+        (inserted.rejected[0].position, inserted.rejected[0].input),
+        (2, Some(7))
+    );
+    assert_eq!(inserted.rejected[0].code, "23505");
+
+    test_db::drop_table(&url, table).await
+}
+
+#[test]
+fn test_rejected_row_names_input_and_detail() {
+    let rejected = RejectedRow {
+        table: "\"public\".\"orders\"".to_string(),
+        input: Some(7),
+        position: 3,
+        row: json!({ "id": 1 }),
+        code: "23505".to_string(),
+        message: "duplicate key value violates unique constraint \"orders_pkey\"".to_string(),
+        detail: Some("Key (id)=(1) already exists.".to_string()),
+    };
+
+    assert_eq!(
+        rejected.to_string(),
+        "row 3 for \"public\".\"orders\" from input 7: duplicate key value violates unique constraint \"orders_pkey\" (23505): Key (id)=(1) already exists."
+    );
+}
+
+#[tokio::test]
+async fn test_bisect_writes_good_batch_once() -> Result<()> {
+    let mut writer = FakeWriter::default();
+    let bisected = bisect(&mut writer, 8).await?;
+
+    assert_eq!(bisected.rows, 8);
+    assert!(bisected.rejected.is_empty());
+    assert_eq!(writer.writes, vec![0..8]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bisect_isolates_bad_rows_in_order() -> Result<()> {
+    let mut writer = FakeWriter::rejecting(&[(2, "23505"), (5, "22P02")]);
+    let bisected = bisect(&mut writer, 8).await?;
+
+    assert_eq!(bisected.rows, 6);
+    assert_eq!(
+        rejected_positions(&bisected.rejected),
+        vec![
+            (2, "rejected 23505".to_string()),
+            (5, "rejected 22P02".to_string())
+        ]
+    );
+    assert_eq!(
+        writer.writes,
+        vec![
+            0..8,
+            0..1,
+            1..4,
+            4..8,
+            1..2,
+            2..4,
+            2..3,
+            3..4,
+            4..6,
+            6..8,
+            4..5,
+            5..6
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bisect_rejects_a_few_rows_with_the_same_error() -> Result<()> {
+    let mut writer = FakeWriter::rejecting(&[(1, "23505"), (6, "23505")]);
+    let bisected = bisect(&mut writer, 8).await?;
+
+    assert_eq!(bisected.rows, 6);
+    let positions: Vec<_> = bisected.rejected.iter().map(|(p, _)| *p).collect();
+    assert_eq!(positions, vec![1, 6]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bisect_rejects_sparse_bad_rows_in_a_large_batch() -> Result<()> {
+    let positions = [3, 97, 98, 250, 333, 512, 640, 777, 901, 999];
+    let bad: Vec<_> = positions.iter().map(|&row| (row, "23505")).collect();
+    let mut writer = FakeWriter::rejecting(&bad);
+    let bisected = bisect(&mut writer, 1000).await?;
+
+    assert_eq!(bisected.rows, 990);
+    let rejected: Vec<_> = bisected.rejected.iter().map(|(p, _)| *p).collect();
+    assert_eq!(rejected, positions);
+    assert!(bisected.unwritten.is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bisect_fails_batch_when_the_first_and_last_rows_fail_alike() -> Result<()> {
+    let bad: Vec<_> = (0..1000).map(|row| (row, "23502")).collect();
+    let mut writer = FakeWriter::rejecting(&bad);
+    let error = bisect(&mut writer, 1000).await.unwrap_err();
+
+    assert_eq!(error.to_string(), "rejected 23502");
+    assert_eq!(writer.writes, vec![0..1000, 0..1, 999..1000]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bisect_rejects_a_bad_first_row() -> Result<()> {
+    let mut writer = FakeWriter::rejecting(&[(0, "23505")]);
+    let bisected = bisect(&mut writer, 8).await?;
+
+    assert_eq!(bisected.rows, 7);
+    let rejected: Vec<_> = bisected.rejected.iter().map(|(p, _)| *p).collect();
+    assert_eq!(rejected, vec![0]);
+    assert_eq!(writer.writes, vec![0..8, 0..1, 7..8, 1..7]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bisect_fails_on_other_errors_before_writing() -> Result<()> {
+    let mut writer = FakeWriter {
+        fail_after: Some(1),
+        ..FakeWriter::rejecting(&[(3, "23505")])
+    };
+    let error = bisect(&mut writer, 8).await.unwrap_err();
+
+    assert_eq!(error.to_string(), "connection closed");
+    assert_eq!(writer.writes, vec![0..8, 0..1]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bisect_reports_unwritten_rows_after_other_errors() -> Result<()> {
+    let mut writer = FakeWriter {
+        fail_after: Some(3),
+        ..FakeWriter::rejecting(&[(5, "23505")])
+    };
+    let bisected = bisect(&mut writer, 8).await?;
+
+    assert_eq!(bisected.rows, 4);
+    assert!(bisected.rejected.is_empty());
+    let (rows, error) = bisected.unwritten.expect("rows should be left unwritten");
+    assert_eq!(rows, vec![4..8]);
+    assert_eq!(error.to_string(), "connection closed");
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use anyhow::Result;
+use kafka_postgres_transform::deno::TransformResult;
+use kafka_postgres_transform::postgres::{Pool, PoolOptions, insert_data};
+use serde_json::json;
+use std::time::Duration;
+
+// Results are checked before a connection is needed, so nothing here needs a running Postgres
+fn unreachable_pool() -> Result<Pool> {
+    let options = PoolOptions {
+        wait_timeout: Some(Duration::from_millis(200)),
+        create_timeout: Some(Duration::from_millis(200)),
+        ..PoolOptions::default()
+    };
+    Pool::new(
+        "postgres://postgres@127.0.0.1:1/postgres?sslmode=disable",
+        &options,
+    )
+}
+
+fn tables_result(outcomes: serde_json::Value) -> Result<TransformResult> {
+    Ok(serde_json::from_value(json!({
+        "success": true,
+        "tables": [{
+            "table_info": {
+                "name": "orders",
+                "schema": "public",
+                "columns": [{ "name": "id", "type": "int4" }]
+            },
+            "rows": [{ "id": 1 }]
+        }],
+        "outcomes": outcomes
+    }))?)
+}
+
+#[tokio::test]
+async fn test_ok_outcome_rows_without_table_info_are_an_error() -> Result<()> {
+    let pool = unreachable_pool()?;
+    let result = tables_result(json!([
+        { "index": 0, "status": "ok", "rows": [{ "id": 2 }] }
+    ]))?;
+
+    let err = insert_data(&pool, &result).await.unwrap_err();
+    assert!(
+        err.to_string().contains("no top-level table_info"),
+        "unexpected error: {err:#}"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tables_with_failed_outcomes_are_written() -> Result<()> {
+    let pool = unreachable_pool()?;
+    let result = tables_result(json!([
+        { "index": 0, "status": "ok" },
+        { "index": 1, "status": "failed", "error": "Missing order id" }
+    ]))?;
+
+    // The result is accepted and only fails for want of a database
+    let err = insert_data(&pool, &result).await.unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("Failed to get a PostgreSQL connection"),
+        "unexpected error: {err:#}"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_group_inputs_must_match_its_rows() -> Result<()> {
+    let pool = unreachable_pool()?;
+    let result: TransformResult = serde_json::from_value(json!({
+        "success": true,
+        "tables": [{
+            "table_info": { "name": "orders", "schema": "public", "columns": [] },
+            "rows": [{ "id": 1 }, { "id": 2 }],
+            "inputs": [0]
+        }]
+    }))?;
+
+    let err = insert_data(&pool, &result).await.unwrap_err();
+    assert!(
+        err.to_string().contains("has 2 rows but 1 inputs"),
+        "unexpected error: {err:#}"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tables_outside_allowed_tables_are_rejected() -> Result<()> {
+    let result = tables_result(json!([]))?;
+
+    let pool = unreachable_pool()?.with_allowed_tables(&["public.customers".to_string()]);
+    let err = insert_data(&pool, &result).await.unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("Table public.orders is not in the list of allowed tables"),
+        "unexpected error: {err:#}"
+    );
+
+    // A schema-less entry means the public schema, and lets the result through to the database
+    let pool = unreachable_pool()?.with_allowed_tables(&["orders".to_string()]);
+    let err = insert_data(&pool, &result).await.unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("Failed to get a PostgreSQL connection"),
+        "unexpected error: {err:#}"
+    );
+    Ok(())
+}
@@ -0,0 +1,200 @@
+use anyhow::Result;
+use kafka_postgres_transform::deno::{TableInfo, TransformResult};
+use kafka_postgres_transform::pg_value::{ColumnType, ScalarType};
+use kafka_postgres_transform::postgres::insert_data;
+use kafka_postgres_transform::schema::{DbColumn, resolve_columns};
+use serde_json::json;
+
+mod test_db;
+
+fn orders() -> Result<TableInfo> {
+    Ok(serde_json::from_value(json!({
+        "name": "orders",
+        "schema": "public",
+        "columns": [
+            { "name": "order_id", "type": "string" },
+            { "name": "total_items", "type": "integer" },
+            { "name": "tags", "type": "text[]" }
+        ]
+    }))?)
+}
+
+fn db_column(name: &str, udt_name: &str, nullable: bool) -> DbColumn {
+    DbColumn {
+        name: name.to_string(),
+        column_type: ColumnType::from_udt_name(udt_name),
+        nullable,
+    }
+}
+
+#[test]
+fn test_udt_names() {
+    assert_eq!(
+        ColumnType::from_udt_name("int8"),
+        Some(ColumnType::Scalar(ScalarType::BigInt))
+    );
+    assert_eq!(
+        ColumnType::from_udt_name("bpchar"),
+        Some(ColumnType::Scalar(ScalarType::Text))
+    );
+    assert_eq!(
+        ColumnType::from_udt_name("_int4"),
+        Some(ColumnType::Array(ScalarType::Int))
+    );
+    assert_eq!(ColumnType::from_udt_name("tsvector"), None);
+}
+
+#[test]
+fn test_coerces_to_table_types() -> Result<()> {
+    let columns = resolve_columns(
+        &orders()?,
+        &[
+            db_column("order_id", "varchar", false),
+            db_column("total_items", "int8", true),
+            db_column("tags", "_text", true),
+            db_column("created_at", "timestamptz", false),
+        ],
+    )?;
+
+    assert_eq!(columns[0].r#type, "string");
+    assert!(!columns[0].nullable);
+    assert_eq!(columns[1].r#type, "int8");
+    assert!(columns[1].nullable);
+    assert_eq!(columns[2].r#type, "text[]");
+    Ok(())
+}
+
+#[test]
+fn test_unknown_table_types_are_left_as_declared() -> Result<()> {
+    let columns = resolve_columns(
+        &orders()?,
+        &[
+            db_column("order_id", "citext", true),
+            db_column("total_items", "int4", true),
+            db_column("tags", "_text", true),
+        ],
+    )?;
+
+    assert_eq!(columns[0].r#type, "string");
+    Ok(())
+}
+
+#[test]
+fn test_rejects_missing_column() -> Result<()> {
+    let err = resolve_columns(
+        &orders()?,
+        &[
+            db_column("order_id", "text", true),
+            db_column("tags", "_text", true),
+        ],
+    )
+    .unwrap_err();
+
+    assert_eq!(
+        err.to_string(),
+        "Column total_items does not exist in public.orders"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_rejects_array_mismatch() -> Result<()> {
+    let err = resolve_columns(
+        &orders()?,
+        &[
+            db_column("order_id", "text", true),
+            db_column("total_items", "int4", true),
+            db_column("tags", "text", true),
+        ],
+    )
+    .unwrap_err();
+
+    assert_eq!(
+        err.to_string(),
+        "Column tags of public.orders is declared as text[] but is text in the table"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_prefetch_allowed_tables_fails_on_missing_table() -> Result<()> {
+    let Some(url) = test_db::database_url("test_prefetch_allowed_tables_fails_on_missing_table")
+    else {
+        return Ok(());
+    };
+    let table = "prefetch_allowed";
+    test_db::create_table(&url, table, "id int4").await?;
+
+    let allowed = [table.to_string()];
+    test_db::pool(&url)?
+        .with_allowed_tables(&allowed)
+        .prefetch_allowed_tables()
+        .await?;
+
+    let missing = [table.to_string(), "public.prefetch_missing".to_string()];
+    let error = test_db::pool(&url)?
+        .with_allowed_tables(&missing)
+        .prefetch_allowed_tables()
+        .await
+        .unwrap_err();
+    assert!(
+        format!("{error:#}").contains("public.prefetch_missing does not exist"),
+        "Unexpected error: {error:#}"
+    );
+
+    // The first batch creates missing tables when auto-migrating
+    test_db::pool(&url)?
+        .with_allowed_tables(&missing)
+        .with_auto_migrate(true)
+        .prefetch_allowed_tables()
+        .await?;
+
+    test_db::drop_table(&url, table).await
+}
+
+#[tokio::test]
+async fn test_groups_without_rows_are_not_checked_or_migrated() -> Result<()> {
+    let Some(url) = test_db::database_url("test_groups_without_rows_are_not_checked_or_migrated")
+    else {
+        return Ok(());
+    };
+    let (table, empty) = ("empty_group_written", "empty_group_missing");
+    test_db::create_table(&url, table, "id int4").await?;
+    test_db::drop_table(&url, empty).await?;
+
+    let result: TransformResult = serde_json::from_value(json!({
+        "success": true,
+        "tables": [
+            {
+                "table_info": {
+                    "name": table,
+                    "schema": "public",
+                    "columns": [{ "name": "id", "type": "int4" }]
+                },
+                "rows": [{ "id": 1 }]
+            },
+            {
+                "table_info": {
+                    "name": empty,
+                    "schema": "public",
+                    "columns": [{ "name": "id", "type": "int4" }]
+                },
+                "rows": []
+            }
+        ]
+    }))?;
+
+    let pool = test_db::pool(&url)?;
+    assert_eq!(insert_data(&pool, &result).await?.rows, 1);
+
+    let pool = test_db::pool(&url)?.with_auto_migrate(true);
+    assert_eq!(insert_data(&pool, &result).await?.rows, 1);
+    let client = test_db::connect(&url).await?;
+    let created = client
+        .query_opt(&format!("SELECT to_regclass('public.{empty}')::text"), &[])
+        .await?
+        .and_then(|row| row.get::<_, Option<String>>(0));
+    assert_eq!(created, None);
+
+    test_db::drop_table(&url, table).await
+}
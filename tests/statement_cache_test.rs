@@ -0,0 +1,82 @@
+use kafka_postgres_transform::statement_cache::{ConnectionLru, is_stale_statement};
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use tokio_postgres::error::SqlState;
+
+fn cache(capacity: usize) -> ConnectionLru<u8, i32> {
+    ConnectionLru::new(NonZeroUsize::new(capacity).unwrap())
+}
+
+#[test]
+fn test_stale_statement_errors() {
+    assert!(is_stale_statement(
+        &SqlState::INVALID_SQL_STATEMENT_NAME,
+        "prepared statement \"s1\" does not exist"
+    ));
+    assert!(is_stale_statement(
+        &SqlState::FEATURE_NOT_SUPPORTED,
+        "cached plan must not change result type"
+    ));
+    assert!(!is_stale_statement(
+        &SqlState::FEATURE_NOT_SUPPORTED,
+        "something else is not supported"
+    ));
+    assert!(!is_stale_statement(
+        &SqlState::UNIQUE_VIOLATION,
+        "duplicate key value violates unique constraint"
+    ));
+}
+
+#[test]
+fn test_least_recently_used_statement_is_evicted() {
+    let mut cache = cache(2);
+    let connection = Arc::new(0);
+
+    cache.put(&connection, "a".to_string(), 1);
+    cache.put(&connection, "b".to_string(), 2);
+    assert_eq!(cache.get(&connection, "a"), Some(1));
+    cache.put(&connection, "c".to_string(), 3);
+
+    assert_eq!(cache.get(&connection, "a"), Some(1));
+    assert_eq!(cache.get(&connection, "b"), None);
+    assert_eq!(cache.get(&connection, "c"), Some(3));
+
+    cache.remove(&connection, "a");
+    assert_eq!(cache.get(&connection, "a"), None);
+}
+
+#[test]
+fn test_statements_are_kept_per_connection() {
+    let mut cache = cache(2);
+    let first = Arc::new(0);
+    let second = Arc::new(0);
+
+    cache.put(&first, "a".to_string(), 1);
+    assert_eq!(cache.get(&second, "a"), None);
+
+    cache.put(&second, "a".to_string(), 2);
+    assert_eq!(cache.get(&first, "a"), Some(1));
+    assert_eq!(cache.get(&second, "a"), Some(2));
+}
+
+#[test]
+fn test_closed_connections_are_forgotten() {
+    let mut cache = cache(2);
+    let closed = Arc::new(0);
+    let open = Arc::new(0);
+
+    cache.put(&closed, "a".to_string(), 1);
+    cache.put(&open, "a".to_string(), 2);
+    assert_eq!(cache.tracked_connections(), 2);
+
+    drop(closed);
+    let new = Arc::new(0);
+    cache.put(&new, "a".to_string(), 3);
+
+    assert_eq!(cache.tracked_connections(), 2);
+    assert_eq!(cache.get(&open, "a"), Some(2));
+    assert_eq!(cache.get(&new, "a"), Some(3));
+
+    cache.clear();
+    assert_eq!(cache.tracked_connections(), 0);
+}
@@ -0,0 +1,71 @@
+//! Postgres access for tests that need a database, which only run when `TEST_DATABASE_URL` is set
+#![allow(dead_code)]
+
+use anyhow::Result;
+use kafka_postgres_transform::deno::TransformResult;
+use kafka_postgres_transform::postgres::{Pool, PoolOptions};
+use serde_json::{Value, json};
+use tokio_postgres::{Client, NoTls};
+
+/// Connection string of the test database, or `None` after logging that the test is skipped
+pub fn database_url(test: &str) -> Option<String> {
+    match std::env::var("TEST_DATABASE_URL") {
+        Ok(url) => Some(url),
+        Err(_) => {
+            println!("Skipping {test}: TEST_DATABASE_URL is not set");
+            None
+        }
+    }
+}
+
+pub fn pool(url: &str) -> Result<Pool> {
+    Pool::new(url, &PoolOptions::default())
+}
+
+pub async fn connect(url: &str) -> Result<Client> {
+    let (client, connection) = tokio_postgres::connect(url, NoTls).await?;
+    tokio::spawn(connection);
+    Ok(client)
+}
+
+/// Drops and creates a table in the public schema, named after the test so tests can run at once
+pub async fn create_table(url: &str, name: &str, columns: &str) -> Result<()> {
+    let client = connect(url).await?;
+    client
+        .batch_execute(&format!(
+            "DROP TABLE IF EXISTS public.{name}; CREATE TABLE public.{name} ({columns})"
+        ))
+        .await?;
+    Ok(())
+}
+
+pub async fn drop_table(url: &str, name: &str) -> Result<()> {
+    let client = connect(url).await?;
+    client
+        .batch_execute(&format!("DROP TABLE IF EXISTS public.{name}"))
+        .await?;
+    Ok(())
+}
+
+/// Ids in the table as seen from a connection of its own, so uncommitted rows don't show
+pub async fn ids(url: &str, name: &str) -> Result<Vec<i32>> {
+    let client = connect(url).await?;
+    let rows = client
+        .query(&format!("SELECT id FROM public.{name} ORDER BY id"), &[])
+        .await?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// A result writing the given ids to a table with a single `id` column
+pub fn id_rows(table: &str, ids: &[i32]) -> Result<TransformResult> {
+    let data: Vec<Value> = ids.iter().map(|id| json!({ "id": id })).collect();
+    Ok(serde_json::from_value(json!({
+        "success": true,
+        "table_info": {
+            "name": table,
+            "schema": "public",
+            "columns": [{ "name": "id", "type": "int4", "nullable": false }]
+        },
+        "data": data
+    }))?)
+}